@@ -0,0 +1,18 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+use moonshine_processor::client::Pool;
+
+use crate::handlers::ApiError;
+
+pub async fn handle(
+    State(pool): State<Pool>,
+) -> Result<impl IntoResponse, ApiError> {
+    let conn = pool.get().await.map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let result = conn.circuit_breakers().await?;
+
+    Ok((
+        StatusCode::OK,
+        [("content-type", "application/json")],
+        result
+    ))
+}
@@ -1,17 +1,27 @@
 use axum::extract::State;
-use axum::http::StatusCode;
+use axum::http::{header, StatusCode};
 use axum::Json;
 use axum::response::IntoResponse;
 
 use moonshine_processor::client::Pool;
+use moonshine_processor::cmd::PutStatus;
 use moonshine_processor::processor::Payment;
 
+use crate::handlers::ApiError;
+
 pub async fn handle(
     State(pool): State<Pool>,
     Json(payment): Json<Payment>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let mut conn = pool.get().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    conn.put_payment(&payment).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<impl IntoResponse, ApiError> {
+    let conn = pool.get().await.map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    let status = conn.put_payment(&payment).await?;
 
-    Ok(StatusCode::CREATED)
+    match status {
+        PutStatus::Accepted => Ok(StatusCode::CREATED.into_response()),
+        PutStatus::Duplicate => Ok(StatusCode::CONFLICT.into_response()),
+        PutStatus::Rejected(retry_after) => Ok((
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, retry_after.as_secs().to_string())],
+        ).into_response()),
+    }
 }
@@ -0,0 +1,51 @@
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use moonshine_processor::client::Pool;
+use std::collections::HashMap;
+
+use crate::handlers::ApiError;
+
+const DEFAULT_LIMIT: u16 = 100;
+
+pub async fn list(
+    State(pool): State<Pool>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let limit = match params.get("limit") {
+        Some(limit) => limit.parse::<u16>().map_err(|_| StatusCode::BAD_REQUEST)?,
+        None => DEFAULT_LIMIT,
+    };
+
+    let conn = pool.get().await.map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let result = conn.list_dead_letters(limit).await?;
+
+    Ok((
+        StatusCode::OK,
+        [("content-type", "application/json")],
+        result
+    ))
+}
+
+pub async fn requeue(
+    State(pool): State<Pool>,
+    Path(correlation_id): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let conn = pool.get().await.map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let found = conn.requeue_dead_letter(&correlation_id).await?;
+
+    Ok(if found { StatusCode::NO_CONTENT } else { StatusCode::NOT_FOUND })
+}
+
+pub async fn discard(
+    State(pool): State<Pool>,
+    Path(correlation_id): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let conn = pool.get().await.map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let found = conn.discard_dead_letter(&correlation_id).await?;
+
+    Ok(if found { StatusCode::NO_CONTENT } else { StatusCode::NOT_FOUND })
+}
@@ -0,0 +1,34 @@
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::IntoResponse;
+use serde::Serialize;
+
+use moonshine_processor::client::Pool;
+use moonshine_processor::tracker::PaymentState;
+
+use crate::handlers::ApiError;
+
+#[derive(Serialize)]
+struct PaymentStatus {
+    #[serde(rename = "correlationId")]
+    correlation_id: String,
+    state: PaymentState,
+    processor: Option<String>,
+}
+
+pub async fn handle(
+    State(pool): State<Pool>,
+    Path(correlation_id): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let conn = pool.get().await.map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let (state, processor) = conn.get_payment_state(&correlation_id).await?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(PaymentStatus {
+        correlation_id,
+        state,
+        processor,
+    }))
+}
@@ -3,17 +3,18 @@ use chrono::{DateTime, Utc};
 use moonshine_processor::client::Pool;
 use std::collections::HashMap;
 
+use crate::handlers::ApiError;
+
 pub async fn handle(
     State(pool): State<Pool>,
     Query(params): Query<HashMap<String, String>>,
-) -> Result<impl IntoResponse, StatusCode> {
+) -> Result<impl IntoResponse, ApiError> {
     let from = parse_date(params.get("from"), "2025-01-01T00:00:00Z")?;
     let to = parse_date(params.get("to"), "2030-12-01T00:00:00Z")?;
 
-    let mut conn = pool.get().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let conn = pool.get().await.map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
 
-    let result = conn.get_payments_by_date_range(from, to).await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let result = conn.get_payments_by_date_range(from, to).await?;
 
     Ok((
         StatusCode::OK,
@@ -1,3 +1,41 @@
 pub mod reset_handler;
 pub mod create_payment;
+pub mod dead_letters;
+pub mod get_payment;
 pub mod get_payments_summary;
+pub mod routing_stats;
+pub mod circuit_breakers;
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use moonshine_processor::client::ClientError;
+
+/// An error answered to the HTTP client, with the processor's error message
+/// as the body when there is one.
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl From<StatusCode> for ApiError {
+    fn from(status: StatusCode) -> Self {
+        ApiError { status, message: String::new() }
+    }
+}
+
+impl From<ClientError> for ApiError {
+    fn from(e: ClientError) -> Self {
+        let status = match &e {
+            ClientError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ClientError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ClientError::Connection(_) => StatusCode::BAD_GATEWAY,
+        };
+        ApiError { status, message: e.to_string() }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, self.message).into_response()
+    }
+}
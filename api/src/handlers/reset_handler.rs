@@ -3,12 +3,14 @@ use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use moonshine_processor::client::Pool;
 
+use crate::handlers::ApiError;
+
 pub async fn handle(
     State(pool): State<Pool>,
-) -> Result<impl IntoResponse, StatusCode> {
+) -> Result<impl IntoResponse, ApiError> {
 
-    let mut conn = pool.get().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let conn = pool.get().await.map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
 
-    conn.purge().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    conn.purge().await?;
     Ok(())
 }
@@ -3,14 +3,14 @@ mod handlers;
 use std::env;
 use std::os::unix::fs::PermissionsExt;
 
-use axum::routing::post;
+use axum::routing::{delete, post};
 use axum::{routing::get, Router};
-use deadpool::Runtime;
 use tokio::net::UnixListener;
 use tokio::signal;
 
-use moonshine_processor::client::{Manager, Pool};
-use crate::handlers::{create_payment, get_payments_summary, reset_handler};
+use moonshine_processor::client::Pool;
+use moonshine_processor::protocol::FrameConfig;
+use crate::handlers::{circuit_breakers, create_payment, dead_letters, get_payment, get_payments_summary, reset_handler, routing_stats};
 
 #[tokio::main]
 async fn main() {
@@ -22,17 +22,19 @@ async fn main() {
     env_logger::init();
 
     let processor_uds_path = env::var("PROCESSOR_UDS_PATH").unwrap_or("/tmp/moonshine-processor".to_string());
-    let manager = Manager::new(processor_uds_path);
-    let pool = Pool::builder(manager)
-        .max_size(10)
-        .runtime(Runtime::Tokio1)
-        .build()
-        .unwrap();
+    let frame_config = FrameConfig::from_env().unwrap();
+    let pool = Pool::new(processor_uds_path, frame_config, 4);
 
     let app = Router::new()
         .route("/payments", post(create_payment::handle))
+        .route("/payments/{correlationId}", get(get_payment::handle))
         .route("/payments-summary", get(get_payments_summary::handle))
         .route("/purge-payments", post(reset_handler::handle))
+        .route("/admin/dead-letters", get(dead_letters::list))
+        .route("/admin/dead-letters/{correlationId}", delete(dead_letters::discard))
+        .route("/admin/dead-letters/{correlationId}/requeue", post(dead_letters::requeue))
+        .route("/admin/routing", get(routing_stats::handle))
+        .route("/admin/circuit-breakers", get(circuit_breakers::handle))
         .with_state(pool);
 
     let uds_path = env::var("UDS_PATH").unwrap_or("/tmp/moonshine-api".to_string());
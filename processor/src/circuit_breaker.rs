@@ -0,0 +1,212 @@
+use std::env;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::endpoint_stats::WindowStats;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    /// How far back payment outcomes are looked at.
+    pub window: Duration,
+    /// Requests needed in the window before the error rate is trusted.
+    pub min_requests: u64,
+    /// Error rate in the window that opens the breaker.
+    pub error_rate: f64,
+    /// How long an open breaker waits before letting probes through.
+    pub cooldown: Duration,
+    /// Probes allowed in flight at once while half-open.
+    pub half_open_probes: u32,
+    /// Successful probes needed to close the breaker again.
+    pub half_open_successes: u32,
+}
+
+impl BreakerConfig {
+    /// Reads `BREAKER_WINDOW_MS`, `BREAKER_MIN_REQUESTS`, `BREAKER_ERROR_RATE`,
+    /// `BREAKER_COOLDOWN_MS`, `BREAKER_HALF_OPEN_PROBES` and
+    /// `BREAKER_HALF_OPEN_SUCCESSES`.
+    pub fn from_env() -> crate::Result<BreakerConfig> {
+        let window = env::var("BREAKER_WINDOW_MS")
+            .unwrap_or("2000".to_string())
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid BREAKER_WINDOW_MS: {}", e))?;
+        let min_requests = env::var("BREAKER_MIN_REQUESTS")
+            .unwrap_or("10".to_string())
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid BREAKER_MIN_REQUESTS: {}", e))?;
+        let error_rate = env::var("BREAKER_ERROR_RATE")
+            .unwrap_or("0.5".to_string())
+            .parse::<f64>()
+            .map_err(|e| format!("Invalid BREAKER_ERROR_RATE: {}", e))?;
+        let cooldown = env::var("BREAKER_COOLDOWN_MS")
+            .unwrap_or("5000".to_string())
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid BREAKER_COOLDOWN_MS: {}", e))?;
+        let half_open_probes = env::var("BREAKER_HALF_OPEN_PROBES")
+            .unwrap_or("1".to_string())
+            .parse::<u32>()
+            .map_err(|e| format!("Invalid BREAKER_HALF_OPEN_PROBES: {}", e))?;
+        let half_open_successes = env::var("BREAKER_HALF_OPEN_SUCCESSES")
+            .unwrap_or("3".to_string())
+            .parse::<u32>()
+            .map_err(|e| format!("Invalid BREAKER_HALF_OPEN_SUCCESSES: {}", e))?;
+
+        Ok(BreakerConfig {
+            window: Duration::from_millis(window.max(1)),
+            min_requests: min_requests.max(1),
+            error_rate,
+            cooldown: Duration::from_millis(cooldown),
+            half_open_probes: half_open_probes.max(1),
+            half_open_successes: half_open_successes.max(1),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Traffic flows normally.
+    Closed,
+    /// No traffic until the cool-down is over.
+    Open,
+    /// A limited number of probes decide whether to close or reopen.
+    HalfOpen,
+}
+
+/// A request let through by a circuit breaker, handed back with its outcome.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Admission {
+    /// When sent as a probe, the `times_opened` of the half-open period it
+    /// probes for.
+    probe_of: Option<u64>,
+}
+
+/// Stops routing to an endpoint as soon as its real payments start failing,
+/// without waiting for the next active health check.
+///
+/// An open breaker turns half-open once its cool-down is over, or earlier if
+/// a health check started after it opened reports the endpoint healthy.
+/// While half-open, only `half_open_probes` requests are let through at a
+/// time; `half_open_successes` successful ones close it, any failure reopens
+/// it. Every method that changes the state returns the new one.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: BreakerState,
+    opened_at: Instant,
+    probes_in_flight: u32,
+    probe_successes: u32,
+    /// Unix timestamp in milliseconds of the last state change.
+    changed_at: i64,
+    times_opened: u64,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker {
+            state: BreakerState::Closed,
+            opened_at: Instant::now(),
+            probes_in_flight: 0,
+            probe_successes: 0,
+            changed_at: chrono::Utc::now().timestamp_millis(),
+            times_opened: 0,
+        }
+    }
+}
+
+impl CircuitBreaker {
+    pub fn state(&self) -> BreakerState {
+        self.state
+    }
+
+    pub fn changed_at(&self) -> i64 {
+        self.changed_at
+    }
+
+    pub fn probes_in_flight(&self) -> u32 {
+        self.probes_in_flight
+    }
+
+    pub fn times_opened(&self) -> u64 {
+        self.times_opened
+    }
+
+    /// Turns an open breaker half-open once its cool-down is over.
+    pub fn poll(&mut self, config: &BreakerConfig, now: Instant) -> Option<BreakerState> {
+        if self.state == BreakerState::Open && now.duration_since(self.opened_at) >= config.cooldown {
+            return self.transition(BreakerState::HalfOpen, now);
+        }
+        None
+    }
+
+    /// Whether a request may be sent now. A half-open breaker hands out a
+    /// probe slot, given back by the request's `on_outcome`.
+    pub fn try_admit(&mut self, config: &BreakerConfig) -> Option<Admission> {
+        match self.state {
+            BreakerState::Closed => Some(Admission { probe_of: None }),
+            BreakerState::Open => None,
+            BreakerState::HalfOpen if self.probes_in_flight < config.half_open_probes => {
+                self.probes_in_flight += 1;
+                Some(Admission { probe_of: Some(self.times_opened) })
+            }
+            BreakerState::HalfOpen => None,
+        }
+    }
+
+    pub fn on_outcome(
+        &mut self,
+        admission: Admission,
+        success: bool,
+        window: &WindowStats,
+        config: &BreakerConfig,
+        now: Instant,
+    ) -> Option<BreakerState> {
+        match self.state {
+            BreakerState::Closed => {
+                if window.requests < config.min_requests
+                    || (window.failures as f64) < config.error_rate * window.requests as f64
+                {
+                    return None;
+                }
+                self.transition(BreakerState::Open, now)
+            }
+            // A request sent before the breaker opened.
+            BreakerState::Open => None,
+            // Only this period's probes decide; requests sent before it
+            // opened say nothing about the recovery.
+            BreakerState::HalfOpen if admission.probe_of != Some(self.times_opened) => None,
+            BreakerState::HalfOpen => {
+                self.probes_in_flight = self.probes_in_flight.saturating_sub(1);
+                if !success {
+                    return self.transition(BreakerState::Open, now);
+                }
+
+                self.probe_successes += 1;
+                if self.probe_successes < config.half_open_successes {
+                    return None;
+                }
+                self.transition(BreakerState::Closed, now)
+            }
+        }
+    }
+
+    /// Lets probes through early when a health check started after the
+    /// breaker opened reports the endpoint healthy.
+    pub fn on_health_check(&mut self, healthy: bool, checked_at: Instant) -> Option<BreakerState> {
+        if self.state != BreakerState::Open || !healthy || checked_at < self.opened_at {
+            return None;
+        }
+        self.transition(BreakerState::HalfOpen, checked_at)
+    }
+
+    fn transition(&mut self, state: BreakerState, now: Instant) -> Option<BreakerState> {
+        if state == BreakerState::Open {
+            self.opened_at = now;
+            self.times_opened += 1;
+        }
+        self.state = state;
+        self.probes_in_flight = 0;
+        self.probe_successes = 0;
+        self.changed_at = chrono::Utc::now().timestamp_millis();
+        Some(state)
+    }
+}
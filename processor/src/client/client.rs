@@ -1,59 +1,336 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
 use tokio::net::UnixStream;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{mpsc, oneshot};
 use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::fmt;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use crate::cmd::PutStatus;
 use crate::processor::Payment;
+use crate::protocol::{self, FrameConfig, ProtocolError, ResponseStatus};
+use crate::tracker::PaymentState;
+use crate::PublishedHealthCheck;
 
+/// Why a command sent to the processor did not succeed.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The processor could not be reached, or the connection broke.
+    Connection(crate::Error),
+    /// The processor could not decode the request.
+    BadRequest(String),
+    /// The processor understood the request but failed to carry it out.
+    Failed(String),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Connection(e) => write!(f, "connection error: {}", e),
+            ClientError::BadRequest(e) => write!(f, "bad request: {}", e),
+            ClientError::Failed(e) => write!(f, "command failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<crate::Error> for ClientError {
+    fn from(e: crate::Error) -> Self {
+        ClientError::Connection(e)
+    }
+}
+
+impl From<std::io::Error> for ClientError {
+    fn from(e: std::io::Error) -> Self {
+        ClientError::Connection(e.into())
+    }
+}
+
+impl From<ProtocolError> for ClientError {
+    fn from(e: ProtocolError) -> Self {
+        match e {
+            // The oversize payload was skipped, so the connection is still usable.
+            ProtocolError::TooLarge { .. } => ClientError::Failed(e.to_string()),
+            e => ClientError::Connection(e.into()),
+        }
+    }
+}
+
+pub type ClientResult<T> = std::result::Result<T, ClientError>;
+
+/// Requests waiting to be written before callers start to wait for room.
+const REQUEST_BUFFER: usize = 1024;
+
+/// A connection to the processor that many callers can share: requests are
+/// pipelined on one socket and each response is routed back to its caller by
+/// request ID. Clones share the connection, which closes once they are all
+/// dropped.
+#[derive(Clone)]
 pub struct ProcessorClient {
-    stream: BufWriter<UnixStream>,
+    inner: Arc<Connection>,
+}
+
+struct Connection {
+    requests: mpsc::Sender<Vec<u8>>,
+    calls: Arc<PendingCalls>,
+    frame: FrameConfig,
+    next_id: AtomicU32,
+}
+
+/// Callers waiting for a response, by request ID.
+struct PendingCalls {
+    waiting: Mutex<HashMap<u32, oneshot::Sender<ClientResult<protocol::Response>>>>,
+    closed: AtomicBool,
+}
+
+impl PendingCalls {
+    /// Registers a caller, unless the connection is already closed.
+    fn register(&self, id: u32) -> ClientResult<oneshot::Receiver<ClientResult<protocol::Response>>> {
+        let mut waiting = self.waiting.lock().map_err(|_| connection_closed())?;
+        if self.closed.load(Ordering::Acquire) {
+            return Err(connection_closed());
+        }
+        let (tx, rx) = oneshot::channel();
+        waiting.insert(id, tx);
+        Ok(rx)
+    }
+
+    fn complete(&self, id: u32, response: ClientResult<protocol::Response>) {
+        let caller = self.waiting.lock().ok().and_then(|mut waiting| waiting.remove(&id));
+        // The caller may have given up in the meantime.
+        if let Some(caller) = caller {
+            let _ = caller.send(response);
+        }
+    }
+
+    fn cancel(&self, id: u32) {
+        if let Ok(mut waiting) = self.waiting.lock() {
+            waiting.remove(&id);
+        }
+    }
+
+    /// Fails every waiting caller, and every later one.
+    fn close(&self) {
+        if let Ok(mut waiting) = self.waiting.lock() {
+            self.closed.store(true, Ordering::Release);
+            waiting.clear();
+        }
+    }
+}
+
+/// Forgets a caller that stopped waiting before its response arrived.
+struct PendingCall<'a> {
+    calls: &'a PendingCalls,
+    id: u32,
+}
+
+impl Drop for PendingCall<'_> {
+    fn drop(&mut self) {
+        self.calls.cancel(self.id);
+    }
 }
 
 impl ProcessorClient {
-    pub async fn connect<P: AsRef<Path>>(uds_path: P) -> crate::Result<ProcessorClient> {
+    pub async fn connect<P: AsRef<Path>>(uds_path: P, frame: FrameConfig) -> crate::Result<ProcessorClient> {
         let socket = UnixStream::connect(uds_path).await?;
-        let stream = BufWriter::new(socket);
-        Ok(ProcessorClient { stream })
+        let (reader, writer) = socket.into_split();
+
+        let calls = Arc::new(PendingCalls { waiting: Mutex::new(HashMap::new()), closed: AtomicBool::new(false) });
+        let (requests, pending_requests) = mpsc::channel(REQUEST_BUFFER);
+        tokio::spawn(write_requests(BufWriter::new(writer), pending_requests, calls.clone()));
+        tokio::spawn(read_responses(BufReader::new(reader), frame, calls.clone()));
+
+        let inner = Arc::new(Connection { requests, calls, frame, next_id: AtomicU32::new(0) });
+        Ok(ProcessorClient { inner })
     }
 
-    pub async fn purge(&mut self) -> crate::Result<()> {
-        self.stream.write_u8(crate::cmd::CMD_PURGE_OPCODE).await?;
-        self.stream.flush().await?;
+    pub async fn purge(&self) -> ClientResult<()> {
+        self.call(crate::cmd::CMD_PURGE_OPCODE, &[]).await?;
         Ok(())
     }
 
-    pub async fn put_payment(&mut self, payment: &Payment) -> crate::Result<()> {
-        self.stream.write_u8(crate::cmd::CMD_PUT_OPCODE).await?;
-        
+    pub async fn put_payment(&self, payment: &Payment) -> ClientResult<PutStatus> {
         let serialized = bincode::encode_to_vec(payment, bincode::config::standard())
-            .map_err(|e| format!("Failed to serialize payment: {}", e))?;
-        
-        self.stream.write_u16(serialized.len() as u16).await?;
-        self.stream.write_all(&serialized).await?;
-        self.stream.flush().await?;
-        Ok(())
+            .map_err(|e| ClientError::BadRequest(format!("Failed to serialize payment: {}", e)))?;
+
+        let response = self.call(crate::cmd::CMD_PUT_OPCODE, &serialized).await?;
+        match *response.as_slice() {
+            [crate::cmd::put::PUT_ACCEPTED] => Ok(PutStatus::Accepted),
+            [crate::cmd::put::PUT_DUPLICATE] => Ok(PutStatus::Duplicate),
+            [crate::cmd::put::PUT_REJECTED, high, low] => {
+                let retry_after = u16::from_be_bytes([high, low]);
+                Ok(PutStatus::Rejected(Duration::from_secs(retry_after as u64)))
+            }
+            _ => Err(invalid_response(format!("Unknown put status: {:?}", response))),
+        }
     }
 
     pub async fn get_payments_by_date_range(
-        &mut self, 
-        start_date: DateTime<Utc>, 
+        &self,
+        start_date: DateTime<Utc>,
         end_date: DateTime<Utc>
-    ) -> crate::Result<String> {
-        self.stream.write_u8(crate::cmd::CMD_GET_OPCODE).await?;
-        
-        self.stream.write_i64(start_date.timestamp_millis()).await?;
-        self.stream.write_i64(end_date.timestamp_millis()).await?;
-        self.stream.flush().await?;
-        
-        let response_len = self.stream.read_u16().await?;
-        let mut response = vec![0; response_len as usize];
-        self.stream.read_exact(&mut response).await?;
-        
-        String::from_utf8(response)
-            .map_err(|e| format!("Failed to parse response as UTF-8: {}", e).into())
+    ) -> ClientResult<String> {
+        let mut payload = Vec::with_capacity(16);
+        payload.extend_from_slice(&start_date.timestamp_millis().to_be_bytes());
+        payload.extend_from_slice(&end_date.timestamp_millis().to_be_bytes());
+
+        let response = self.call(crate::cmd::CMD_GET_OPCODE, &payload).await?;
+        utf8(response)
+    }
+
+    /// Returns the payment's state and, once it succeeded, the name of the
+    /// processor that took it.
+    pub async fn get_payment_state(&self, correlation_id: &str) -> ClientResult<Option<(PaymentState, Option<String>)>> {
+        let response = self.call(crate::cmd::CMD_STATUS_OPCODE, correlation_id.as_bytes()).await?;
+
+        let Some((&state, name)) = response.split_first() else {
+            return Err(invalid_response("Empty payment status".to_string()));
+        };
+        let state = match state {
+            crate::cmd::status::STATUS_UNKNOWN => return Ok(None),
+            state => PaymentState::from_u8(state).map_err(|e| invalid_response(e.to_string()))?,
+        };
+        if state != PaymentState::Succeeded {
+            return Ok(Some((state, None)));
+        }
+
+        let name = utf8(name.to_vec())?;
+        Ok(Some((state, Some(name))))
+    }
+
+    /// Returns the dead letters as a JSON array.
+    pub async fn list_dead_letters(&self, limit: u16) -> ClientResult<String> {
+        let response = self.call(crate::cmd::CMD_LIST_DEAD_LETTERS_OPCODE, &limit.to_be_bytes()).await?;
+        utf8(response)
+    }
+
+    /// Moves a dead letter back to the pending queue, returning `false` if
+    /// there was none for `correlation_id`.
+    pub async fn requeue_dead_letter(&self, correlation_id: &str) -> ClientResult<bool> {
+        let response = self.call(crate::cmd::CMD_REQUEUE_DEAD_LETTER_OPCODE, correlation_id.as_bytes()).await?;
+        Ok(response == [crate::cmd::DEAD_LETTER_FOUND])
+    }
+
+    /// Drops a dead letter for good, returning `false` if there was none for
+    /// `correlation_id`.
+    pub async fn discard_dead_letter(&self, correlation_id: &str) -> ClientResult<bool> {
+        let response = self.call(crate::cmd::CMD_DISCARD_DEAD_LETTER_OPCODE, correlation_id.as_bytes()).await?;
+        Ok(response == [crate::cmd::DEAD_LETTER_FOUND])
+    }
+
+    /// Returns the routing strategy, its estimated fees saved and the
+    /// latency and error rate observed on each endpoint, as JSON.
+    pub async fn routing_stats(&self) -> ClientResult<String> {
+        let response = self.call(crate::cmd::CMD_ROUTING_STATS_OPCODE, &[]).await?;
+        utf8(response)
+    }
+
+    /// Returns the circuit breaker state of every payment processor, as JSON.
+    pub async fn circuit_breakers(&self) -> ClientResult<String> {
+        let response = self.call(crate::cmd::CMD_CIRCUIT_BREAKERS_OPCODE, &[]).await?;
+        utf8(response)
+    }
+
+    /// Returns the health checks the processor is working with, so follower
+    /// processors need not poll the payment processors themselves.
+    pub async fn get_health_check(&self) -> ClientResult<Vec<PublishedHealthCheck>> {
+        let response = self.call(crate::cmd::CMD_HEALTH_CHECK_OPCODE, &[]).await?;
+        serde_json::from_slice(&response)
+            .map_err(|e| invalid_response(format!("Failed to parse health checks: {}", e)))
+    }
+
+    /// Sends one request and waits for its response, returning the response
+    /// payload if the command succeeded.
+    async fn call(&self, opcode: u8, payload: &[u8]) -> ClientResult<Vec<u8>> {
+        let connection = &self.inner;
+        if payload.len() > connection.frame.max_payload_len {
+            return Err(ClientError::BadRequest(format!(
+                "Request too large: {} bytes, at most {} allowed", payload.len(), connection.frame.max_payload_len
+            )));
+        }
+
+        // The frame is built up front and written by a separate task, so a
+        // caller that gives up cannot leave half a request on the socket.
+        let id = connection.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut request = Vec::with_capacity(payload.len() + 10);
+        protocol::write_request(&mut request, opcode, id, payload).await?;
+
+        let response = connection.calls.register(id)?;
+        let _pending = PendingCall { calls: &connection.calls, id };
+        connection.requests.send(request).await.map_err(|_| connection_closed())?;
+        let response = response.await.map_err(|_| connection_closed())??;
+
+        let message = || String::from_utf8_lossy(&response.payload).into_owned();
+        match response.status {
+            ResponseStatus::Ok => Ok(response.payload),
+            ResponseStatus::BadRequest => Err(ClientError::BadRequest(message())),
+            ResponseStatus::Failed => Err(ClientError::Failed(message())),
+        }
     }
 
     pub fn is_closed(&self) -> bool {
-        self.stream.get_ref().peer_cred().is_err()
+        self.inner.calls.closed.load(Ordering::Acquire)
+    }
+}
+
+/// Writes queued requests, flushing once the queue is empty so pipelined
+/// requests go out together.
+async fn write_requests(
+    mut writer: BufWriter<OwnedWriteHalf>,
+    mut requests: mpsc::Receiver<Vec<u8>>,
+    calls: Arc<PendingCalls>,
+) {
+    while let Some(request) = requests.recv().await {
+        let mut result = writer.write_all(&request).await;
+        while result.is_ok()
+            && let Ok(request) = requests.try_recv()
+        {
+            result = writer.write_all(&request).await;
+        }
+        let result = match result {
+            Ok(()) => writer.flush().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!("Failed to write to processor: {}", e);
+            calls.close();
+            return;
+        }
+    }
+}
+
+/// Hands each response to the caller waiting for it, until the connection
+/// closes or breaks the protocol.
+async fn read_responses(mut reader: BufReader<OwnedReadHalf>, frame: FrameConfig, calls: Arc<PendingCalls>) {
+    loop {
+        match protocol::read_response(&mut reader, frame).await {
+            Ok(response) => calls.complete(response.id, Ok(response)),
+            Err(e @ ProtocolError::TooLarge { id, .. }) => calls.complete(id, Err(e.into())),
+            Err(e) => {
+                debug!("Processor connection closed: {}", e);
+                break;
+            }
+        }
     }
-}
\ No newline at end of file
+    calls.close();
+}
+
+fn connection_closed() -> ClientError {
+    ClientError::Connection("Connection to the processor closed".into())
+}
+
+/// A response that does not follow the protocol.
+fn invalid_response(message: String) -> ClientError {
+    ClientError::Connection(message.into())
+}
+
+fn utf8(response: Vec<u8>) -> ClientResult<String> {
+    String::from_utf8(response)
+        .map_err(|e| invalid_response(format!("Failed to parse response as UTF-8: {}", e)))
+}
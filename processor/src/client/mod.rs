@@ -1,5 +1,6 @@
+#[allow(clippy::module_inception)]
 mod client;
-pub use client::ProcessorClient;
+pub use client::{ClientError, ClientResult, ProcessorClient};
 
 mod pool;
-pub use pool::{Manager, Pool};
+pub use pool::Pool;
@@ -1,42 +1,54 @@
-use deadpool::managed;
-use deadpool::managed::{Metrics, RecycleError};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 use log::warn;
+use tokio::sync::Mutex;
 
 use crate::client::ProcessorClient;
-use crate::Error;
-
-#[derive(Debug)]
-pub struct Manager(String);
-pub type Pool = managed::Pool<Manager>;
-
-impl Manager {
-    pub fn new<S: Into<String>>(uds_path: S) -> Self {
-        Self(uds_path.into())
-    }
+use crate::protocol::FrameConfig;
+
+/// A few connections to the processor, handed out in turn. Since each one
+/// multiplexes its callers, a caller only holds the pool while picking one,
+/// and connections that closed are opened again on the next pick.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<PoolInner>,
 }
 
-impl managed::Manager for Manager {
-    type Type = ProcessorClient;
-    type Error = Error;
+struct PoolInner {
+    uds_path: String,
+    frame: FrameConfig,
+    connections: Vec<Mutex<Option<ProcessorClient>>>,
+    next: AtomicUsize,
+}
 
-    async fn create(&self) -> Result<ProcessorClient, Error> {
-        let client = ProcessorClient::connect(&self.0).await?;
-        Ok(client)
+impl Pool {
+    pub fn new<S: Into<String>>(uds_path: S, frame: FrameConfig, size: usize) -> Self {
+        let connections = (0..size.max(1)).map(|_| Mutex::new(None)).collect();
+        Pool {
+            inner: Arc::new(PoolInner {
+                uds_path: uds_path.into(),
+                frame,
+                connections,
+                next: AtomicUsize::new(0),
+            }),
+        }
     }
 
-    async fn recycle(
-        &self,
-        conn: &mut ProcessorClient,
-        _: &Metrics,
-    ) -> managed::RecycleResult<Error> {
-
-        if conn.is_closed() {
-            warn!("Recycling client");
-            return Err(RecycleError::Message(
-                "Connection is closed. Connection is considered unusable.".into(),
-            ));
+    pub async fn get(&self) -> crate::Result<ProcessorClient> {
+        let pool = &self.inner;
+        let index = pool.next.fetch_add(1, Ordering::Relaxed) % pool.connections.len();
+        let mut connection = pool.connections[index].lock().await;
+
+        if let Some(client) = connection.as_ref() {
+            if !client.is_closed() {
+                return Ok(client.clone());
+            }
+            warn!("Reconnecting to processor");
         }
 
-        Ok(())
+        let client = ProcessorClient::connect(&pool.uds_path, pool.frame).await?;
+        *connection = Some(client.clone());
+        Ok(client)
     }
 }
@@ -0,0 +1,11 @@
+use crate::endpoint_stats::EndpointStatsTracker;
+
+pub struct CircuitBreakers {}
+
+impl CircuitBreakers {
+    pub(crate) async fn execute(self, buffer: &mut Vec<u8>, endpoint_stats: &EndpointStatsTracker) -> crate::Result<()> {
+        serde_json::to_writer(buffer, &endpoint_stats.breakers())
+            .map_err(|e| format!("Failed to serialize circuit breakers: {}", e))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,25 @@
+use std::sync::Arc;
+
+use crate::cmd::{parse_string, DEAD_LETTER_FOUND, DEAD_LETTER_NOT_FOUND};
+use crate::dead_letter::DeadLetterQueue;
+
+pub struct DiscardDeadLetter {
+    correlation_id: String,
+}
+
+impl DiscardDeadLetter {
+    pub(crate) fn parse_data(data: &[u8]) -> crate::Result<DiscardDeadLetter> {
+        let correlation_id = parse_string(data)?;
+        Ok(DiscardDeadLetter { correlation_id })
+    }
+
+    pub(crate) async fn execute(self, buffer: &mut Vec<u8>, dead_letters: &Arc<DeadLetterQueue>) -> crate::Result<()> {
+        let found = dead_letters.take(&self.correlation_id).await?.is_some();
+        if found {
+            log::info!("Discarded dead letter {}", self.correlation_id);
+        }
+
+        buffer.push(if found { DEAD_LETTER_FOUND } else { DEAD_LETTER_NOT_FOUND });
+        Ok(())
+    }
+}
@@ -1,7 +1,6 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
-use tokio::net::UnixStream;
+use std::fmt::Write;
 
-use crate::db::PaymentDb;
+use crate::cmd::App;
 
 pub struct Get {
     start_timestamp: i64,
@@ -9,20 +8,38 @@ pub struct Get {
 }
 
 impl Get {
-    pub(crate) async fn parse_data(stream: &mut BufWriter<UnixStream>) -> crate::Result<Get> {
-        let start_timestamp = stream.read_i64().await?;
-        let end_timestamp = stream.read_i64().await?;
+    pub(crate) fn parse_data(data: &[u8]) -> crate::Result<Get> {
+        let data: [u8; 16] = data.try_into()
+            .map_err(|_| format!("Invalid date range of {} bytes", data.len()))?;
+        let start_timestamp = i64::from_be_bytes(data[..8].try_into()?);
+        let end_timestamp = i64::from_be_bytes(data[8..].try_into()?);
         Ok(Get { start_timestamp, end_timestamp })
     }
 
-    pub(crate) async fn execute(self, buffer: &mut BufWriter<UnixStream>, db: &PaymentDb) -> crate::Result<()> {
-        let payments = db.get_payments_by_date_range(self.start_timestamp, self.end_timestamp)
+    pub(crate) async fn execute(self, buffer: &mut Vec<u8>, app: &App) -> crate::Result<()> {
+        let totals = app.db.get_payments_by_date_range(self.start_timestamp, self.end_timestamp)
             .await
             .map_err(|e| format!("Failed to get payments: {}", e))?;
 
-        buffer.write_u16(payments.len() as u16).await?;
-        buffer.write_all(payments.as_bytes()).await?;
-        
+        // Every processor ever configured is listed, so payments taken by one
+        // since removed from the configuration still add up.
+        let mut payments = String::from("{");
+        for (id, name) in app.processors.names().iter().enumerate() {
+            let totals = totals.get(id).copied().unwrap_or_default();
+            let name = serde_json::to_string(name)
+                .map_err(|e| format!("Failed to serialize processor name: {}", e))?;
+            if id > 0 {
+                payments.push(',');
+            }
+            write!(
+                payments,
+                r#"{}:{{"totalRequests":{},"totalAmount":{}}}"#,
+                name, totals.count, totals.amount
+            )?;
+        }
+        payments.push('}');
+
+        buffer.extend_from_slice(payments.as_bytes());
         Ok(())
     }
 }
\ No newline at end of file
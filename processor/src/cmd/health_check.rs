@@ -0,0 +1,25 @@
+use crate::cmd::App;
+use crate::PublishedHealthCheck;
+
+/// Shares this processor's latest health checks with follower processors.
+pub struct HealthCheck {}
+
+impl HealthCheck {
+    pub(crate) async fn execute(self, buffer: &mut Vec<u8>, app: &App) -> crate::Result<()> {
+        let health = app.db.get_health_check().await?;
+        // Checks keep their timestamp, so followers age them out the same way.
+        let published: Vec<PublishedHealthCheck> = app.processors.active().iter()
+            .zip(&health.checks)
+            .filter_map(|(processor, check)| {
+                check.map(|check| PublishedHealthCheck {
+                    name: processor.name.clone(),
+                    check,
+                })
+            })
+            .collect();
+
+        serde_json::to_writer(buffer, &published)
+            .map_err(|e| format!("Failed to serialize health checks: {}", e))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,20 @@
+use crate::dead_letter::DeadLetterQueue;
+
+pub struct ListDeadLetters {
+    limit: u16,
+}
+
+impl ListDeadLetters {
+    pub(crate) fn parse_data(data: &[u8]) -> crate::Result<ListDeadLetters> {
+        let limit = u16::from_be_bytes(data.try_into()
+            .map_err(|_| format!("Invalid limit of {} bytes", data.len()))?);
+        Ok(ListDeadLetters { limit })
+    }
+
+    pub(crate) async fn execute(self, buffer: &mut Vec<u8>, dead_letters: &DeadLetterQueue) -> crate::Result<()> {
+        let letters = dead_letters.list(self.limit as usize).await?;
+        serde_json::to_writer(buffer, &letters)
+            .map_err(|e| format!("Failed to serialize dead letters: {}", e))?;
+        Ok(())
+    }
+}
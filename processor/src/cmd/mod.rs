@@ -1,48 +1,105 @@
 use std::sync::Arc;
-use tokio::io::{BufWriter};
-use tokio::net::UnixStream;
-use async_channel::{Receiver, Sender};
 
-pub use put::Put;
+pub use put::{Put, PutStatus};
 pub use get::Get;
 pub use purge::Purge;
+pub use status::Status;
+pub use list_dead_letters::ListDeadLetters;
+pub use requeue_dead_letter::RequeueDeadLetter;
+pub use discard_dead_letter::DiscardDeadLetter;
+pub use routing_stats::RoutingStats;
+pub use circuit_breakers::CircuitBreakers;
+pub use health_check::HealthCheck;
 
+use std::time::Duration;
 use crate::db::PaymentDb;
-use crate::processor::Payment;
+use crate::dead_letter::DeadLetterQueue;
+use crate::endpoint_stats::EndpointStatsTracker;
+use crate::processors::Processors;
+use crate::queue::PaymentQueue;
+use crate::tracker::PaymentTracker;
+use crate::workers::concurrency_limiter::{ConcurrencyConfig, ConcurrencyLimiter};
+use crate::workers::endpoint_selector::{EndpointSelector, RoutingConfig};
 
-mod put;
+pub(crate) mod put;
 mod get;
 mod purge;
+pub(crate) mod status;
+mod list_dead_letters;
+mod requeue_dead_letter;
+mod discard_dead_letter;
+mod routing_stats;
+mod circuit_breakers;
+mod health_check;
 
 pub enum Command {
     Put(Put),
     Get(Get),
     Purge(Purge),
+    Status(Status),
+    ListDeadLetters(ListDeadLetters),
+    RequeueDeadLetter(RequeueDeadLetter),
+    DiscardDeadLetter(DiscardDeadLetter),
+    RoutingStats(RoutingStats),
+    CircuitBreakers(CircuitBreakers),
+    HealthCheck(HealthCheck),
 }
 
 
 pub(crate) const CMD_PUT_OPCODE: u8 = 42;
 pub(crate) const CMD_GET_OPCODE: u8 = 43;
 pub(crate) const CMD_PURGE_OPCODE: u8 = 44;
+pub(crate) const CMD_STATUS_OPCODE: u8 = 45;
+pub(crate) const CMD_LIST_DEAD_LETTERS_OPCODE: u8 = 46;
+pub(crate) const CMD_REQUEUE_DEAD_LETTER_OPCODE: u8 = 47;
+pub(crate) const CMD_DISCARD_DEAD_LETTER_OPCODE: u8 = 48;
+pub(crate) const CMD_ROUTING_STATS_OPCODE: u8 = 49;
+pub(crate) const CMD_CIRCUIT_BREAKERS_OPCODE: u8 = 50;
+pub(crate) const CMD_HEALTH_CHECK_OPCODE: u8 = 51;
+
+pub(crate) const DEAD_LETTER_NOT_FOUND: u8 = 0;
+pub(crate) const DEAD_LETTER_FOUND: u8 = 1;
+
+/// Reads a payload made of a single UTF-8 string.
+pub(crate) fn parse_string(data: &[u8]) -> crate::Result<String> {
+    String::from_utf8(data.to_vec())
+        .map_err(|e| format!("Failed to parse string as UTF-8: {}", e).into())
+}
 
 impl Command {
+    /// Runs the command, writing the payload of its response to `buffer`.
     pub(crate) async fn execute(
         self,
-        buffer: &mut BufWriter<UnixStream>,
+        buffer: &mut Vec<u8>,
         app: &App,
     ) -> crate::Result<()> {
         match self {
-            Command::Put(cmd) => cmd.execute(&app.payment_sender).await,
-            Command::Get(cmd) => cmd.execute(buffer, &app.db).await,
-            Command::Purge(cmd) => cmd.execute(&app).await,
+            Command::Put(cmd) => cmd.execute(buffer, app).await,
+            Command::Get(cmd) => cmd.execute(buffer, app).await,
+            Command::Purge(cmd) => cmd.execute(app).await,
+            Command::Status(cmd) => cmd.execute(buffer, app).await,
+            Command::ListDeadLetters(cmd) => cmd.execute(buffer, &app.dead_letters).await,
+            Command::RequeueDeadLetter(cmd) => cmd.execute(buffer, app).await,
+            Command::DiscardDeadLetter(cmd) => cmd.execute(buffer, &app.dead_letters).await,
+            Command::RoutingStats(cmd) => cmd.execute(buffer, app).await,
+            Command::CircuitBreakers(cmd) => cmd.execute(buffer, &app.endpoint_stats).await,
+            Command::HealthCheck(cmd) => cmd.execute(buffer, app).await,
         }
     }
-    pub(crate) async fn from_data(cmd: u8, data: &mut BufWriter<UnixStream>) -> crate::Result<Command> {
+    /// Decodes the command from the payload of its request frame.
+    pub(crate) fn from_data(cmd: u8, data: &[u8]) -> crate::Result<Command> {
 
         let command = match cmd {
-            CMD_PUT_OPCODE => Command::Put(Put::parse_data(data).await?),
-            CMD_GET_OPCODE => Command::Get(Get::parse_data(data).await?),
+            CMD_PUT_OPCODE => Command::Put(Put::parse_data(data)?),
+            CMD_GET_OPCODE => Command::Get(Get::parse_data(data)?),
             CMD_PURGE_OPCODE => Command::Purge(Purge { }),
+            CMD_STATUS_OPCODE => Command::Status(Status::parse_data(data)?),
+            CMD_LIST_DEAD_LETTERS_OPCODE => Command::ListDeadLetters(ListDeadLetters::parse_data(data)?),
+            CMD_REQUEUE_DEAD_LETTER_OPCODE => Command::RequeueDeadLetter(RequeueDeadLetter::parse_data(data)?),
+            CMD_DISCARD_DEAD_LETTER_OPCODE => Command::DiscardDeadLetter(DiscardDeadLetter::parse_data(data)?),
+            CMD_ROUTING_STATS_OPCODE => Command::RoutingStats(RoutingStats { }),
+            CMD_CIRCUIT_BREAKERS_OPCODE => Command::CircuitBreakers(CircuitBreakers { }),
+            CMD_HEALTH_CHECK_OPCODE => Command::HealthCheck(HealthCheck { }),
             _ => return Err(format!("Unknown command: {}", cmd).into()),
         };
 
@@ -53,26 +110,48 @@ impl Command {
 #[derive(Clone)]
 pub struct App {
     pub http_client: reqwest::Client,
-    pub payment_endpoint: String,
-    pub payment_fallback_endpoint: String,
+    pub processors: Arc<Processors>,
     pub db: Arc<PaymentDb>,
-    pub payment_sender: Sender<Payment>,
-    pub payment_receiver: Receiver<Payment>,
+    pub queue: Arc<PaymentQueue>,
+    pub tracker: Arc<PaymentTracker>,
+    pub dead_letters: Arc<DeadLetterQueue>,
+    pub selector: Arc<dyn EndpointSelector>,
+    pub endpoint_stats: Arc<EndpointStatsTracker>,
+    pub health_max_age: Duration,
+    pub concurrency: Arc<ConcurrencyLimiter>,
 }
 
 impl App {
     pub fn new(
-        payment_endpoint: String,
-        payment_fallback_endpoint: String,
+        processors: Processors,
+        db: PaymentDb,
+        queue: PaymentQueue,
+        tracker: PaymentTracker,
+        dead_letters: DeadLetterQueue,
+        routing: RoutingConfig,
+        concurrency: ConcurrencyConfig,
     ) -> Self {
-        let (tx,rx) = async_channel::unbounded();
+        let names = processors.active().iter().map(|p| p.name.clone()).collect();
         App {
             http_client: reqwest::Client::new(),
-            payment_endpoint,
-            payment_fallback_endpoint,
-            db: Arc::new(PaymentDb::new()),
-            payment_sender: tx,
-            payment_receiver: rx,
+            endpoint_stats: Arc::new(EndpointStatsTracker::new(names, routing.breaker)),
+            processors: Arc::new(processors),
+            db: Arc::new(db),
+            queue: Arc::new(queue),
+            tracker: Arc::new(tracker),
+            dead_letters: Arc::new(dead_letters),
+            selector: Arc::from(routing.selector),
+            health_max_age: routing.health_max_age,
+            concurrency: Arc::new(ConcurrencyLimiter::new(concurrency)),
         }
     }
+
+    /// Flushes every write-ahead log to disk.
+    pub async fn sync(&self) -> crate::Result<()> {
+        self.db.sync().await?;
+        self.queue.sync().await?;
+        self.tracker.sync().await?;
+        self.dead_letters.sync().await?;
+        Ok(())
+    }
 }
\ No newline at end of file
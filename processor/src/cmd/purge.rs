@@ -5,8 +5,14 @@ pub struct Purge {}
 
 impl Purge {
     pub(crate) async fn execute(self, app: &App) -> crate::Result<()> {
+        // Clear the queue first so nothing queued before the purge is sent
+        // or recorded after it.
+        app.queue.clear().await?;
+        app.selector.clear();
         payment_client::purge(app).await.ok();
         app.db.clear().await?;
+        app.tracker.clear().await?;
+        app.dead_letters.clear().await?;
         Ok(())
     }
 }
\ No newline at end of file
@@ -1,31 +1,59 @@
-use tokio::io::{AsyncReadExt, BufWriter};
-use tokio::net::UnixStream;
-use async_channel::Sender;
+use std::time::Duration;
 
+use crate::cmd::App;
 use crate::processor::Payment;
 
+pub(crate) const PUT_ACCEPTED: u8 = 0;
+pub(crate) const PUT_DUPLICATE: u8 = 1;
+/// Followed by the u16 number of seconds to wait before retrying.
+pub(crate) const PUT_REJECTED: u8 = 2;
+
+/// Outcome of a `Put`, as reported back to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PutStatus {
+    Accepted,
+    Duplicate,
+    /// The queue is saturated; try again after the given delay.
+    Rejected(Duration),
+}
+
 pub struct Put {
     payment: Payment,
 }
 
 impl Put {
-    pub(crate) async fn parse_data(stream: &mut BufWriter<UnixStream>) -> crate::Result<Put> {
-        let data_size = stream.read_u16().await?;
-        let mut data = vec![0; data_size as usize];
-        stream.read_exact(&mut data).await?;
-        
-        let payment = bincode::decode_from_slice(&data, bincode::config::standard())
+    pub(crate) fn parse_data(data: &[u8]) -> crate::Result<Put> {
+        let payment = bincode::decode_from_slice(data, bincode::config::standard())
             .map_err(|e| format!("Failed to deserialize payment: {}", e))?
             .0;
         
         Ok(Put { payment })
     }
 
-    pub(crate) async fn execute(self, payment_sender: &Sender<Payment>) -> crate::Result<()> {
-        payment_sender.send(self.payment.clone()).await
-            .map_err(|e| format!("Failed to send payment to channel: {}", e))?;
+    pub(crate) async fn execute(self, buffer: &mut Vec<u8>, app: &App) -> crate::Result<()> {
+        let correlation_id = self.payment.correlation_id.clone();
+        let Some(slot) = app.queue.reserve() else {
+            log::debug!("Rejected payment {}: queue saturated", correlation_id);
+            let retry_after = u16::try_from(app.queue.retry_after().as_secs()).unwrap_or(u16::MAX);
+            buffer.push(PUT_REJECTED);
+            buffer.extend_from_slice(&retry_after.to_be_bytes());
+            return Ok(());
+        };
+
+        if !app.tracker.insert(&correlation_id).await? {
+            log::debug!("Rejected duplicate payment: {}", correlation_id);
+            buffer.push(PUT_DUPLICATE);
+            return Ok(());
+        }
+
+        let amount = self.payment.amount;
+        if let Err(e) = app.queue.push(slot, self.payment).await {
+            app.tracker.forget(&correlation_id).await?;
+            return Err(format!("Failed to enqueue payment: {}", e).into());
+        }
         
-        log::debug!("Sent payment to channel: amount: {}", self.payment.amount);
+        log::debug!("Enqueued payment: amount: {}", amount);
+        buffer.push(PUT_ACCEPTED);
         Ok(())
     }
-}
\ No newline at end of file
+}
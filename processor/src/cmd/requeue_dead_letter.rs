@@ -0,0 +1,31 @@
+use crate::cmd::{parse_string, App, DEAD_LETTER_FOUND, DEAD_LETTER_NOT_FOUND};
+use crate::tracker::PaymentState;
+
+pub struct RequeueDeadLetter {
+    correlation_id: String,
+}
+
+impl RequeueDeadLetter {
+    pub(crate) fn parse_data(data: &[u8]) -> crate::Result<RequeueDeadLetter> {
+        let correlation_id = parse_string(data)?;
+        Ok(RequeueDeadLetter { correlation_id })
+    }
+
+    pub(crate) async fn execute(self, buffer: &mut Vec<u8>, app: &App) -> crate::Result<()> {
+        let slot = app.queue.reserve().ok_or("Failed to requeue payment: queue saturated")?;
+        let Some(letter) = app.dead_letters.take(&self.correlation_id).await? else {
+            buffer.push(DEAD_LETTER_NOT_FOUND);
+            return Ok(());
+        };
+
+        if let Err(e) = app.queue.push(slot, letter.payment.clone()).await {
+            app.dead_letters.add(letter).await?;
+            return Err(format!("Failed to requeue payment: {}", e).into());
+        }
+        app.tracker.set_state(&self.correlation_id, PaymentState::Queued).await?;
+
+        log::info!("Requeued dead letter {}", self.correlation_id);
+        buffer.push(DEAD_LETTER_FOUND);
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use serde::Serialize;
+use crate::cmd::App;
+use crate::circuit_breaker::BreakerState;
+use crate::endpoint_stats::{EndpointStats, WindowStats};
+use crate::money::Cents;
+use crate::payment_client;
+
+pub struct RoutingStats {}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RoutingReport<'a> {
+    strategy: &'static str,
+    fees_saved: Option<Cents>,
+    concurrency: ConcurrencyReport,
+    processors: Vec<ProcessorReport<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ConcurrencyReport {
+    limit: usize,
+    in_flight: usize,
+}
+
+#[derive(Serialize)]
+struct ProcessorReport<'a> {
+    name: &'a str,
+    #[serde(flatten)]
+    stats: EndpointStats,
+    window: WindowStats,
+    breaker: BreakerState,
+    #[serde(rename = "timeoutMs")]
+    timeout_ms: u128,
+}
+
+impl RoutingStats {
+    pub(crate) async fn execute(self, buffer: &mut Vec<u8>, app: &App) -> crate::Result<()> {
+        let mut processors = Vec::with_capacity(app.processors.active().len());
+        for (i, processor) in app.processors.active().iter().enumerate() {
+            processors.push(ProcessorReport {
+                name: &processor.name,
+                stats: app.endpoint_stats.get(i),
+                window: app.endpoint_stats.window(i),
+                breaker: app.endpoint_stats.breaker_state(i),
+                timeout_ms: payment_client::payment_timeout(app, i).await.as_millis(),
+            });
+        }
+
+        let report = RoutingReport {
+            strategy: app.selector.name(),
+            fees_saved: app.selector.fees_saved(),
+            concurrency: ConcurrencyReport {
+                limit: app.concurrency.limit(),
+                in_flight: app.concurrency.in_flight(),
+            },
+            processors,
+        };
+        serde_json::to_writer(buffer, &report)
+            .map_err(|e| format!("Failed to serialize routing stats: {}", e))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+use crate::cmd::{parse_string, App};
+use crate::tracker::PaymentState;
+
+/// Written instead of a state byte when the correlation ID is unknown. A
+/// `Succeeded` state byte is followed by the name of the processor, up to the
+/// end of the payload.
+pub(crate) const STATUS_UNKNOWN: u8 = 0xFF;
+
+pub struct Status {
+    correlation_id: String,
+}
+
+impl Status {
+    pub(crate) fn parse_data(data: &[u8]) -> crate::Result<Status> {
+        let correlation_id = parse_string(data)?;
+        Ok(Status { correlation_id })
+    }
+
+    pub(crate) async fn execute(self, buffer: &mut Vec<u8>, app: &App) -> crate::Result<()> {
+        let Some(status) = app.tracker.get(&self.correlation_id).await? else {
+            buffer.push(STATUS_UNKNOWN);
+            return Ok(());
+        };
+
+        buffer.push(status.state as u8);
+        if status.state == PaymentState::Succeeded {
+            let name = status.processor
+                .and_then(|id| app.processors.name(id))
+                .unwrap_or_default();
+            buffer.extend_from_slice(name.as_bytes());
+        }
+        Ok(())
+    }
+}
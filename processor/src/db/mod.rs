@@ -0,0 +1,329 @@
+use crate::processors::ProcessorId;
+use crate::wal::{FsyncPolicy, Wal};
+use crate::{HealthCheckResult, RecordedHealthCheck};
+use crate::money::Cents;
+use bincode::{Decode, Encode};
+use log::{error, info};
+use snapshot::Snapshot;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+pub use snapshot::{SnapshotConfig, Totals};
+
+mod snapshot;
+
+const SEGMENT_PREFIX: &str = "payments-";
+const SEGMENT_SUFFIX: &str = ".wal";
+
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct Payment {
+    pub amount: Cents,
+    pub requested_at: i64,
+    pub processor: ProcessorId,
+}
+
+#[derive(Encode, Decode)]
+struct WalEntry {
+    seq: u64,
+    payment: Payment,
+}
+
+/// Running totals per processor, bucketed by the second the payment was
+/// requested at. Payments are timestamped with whole seconds, so the buckets
+/// answer range queries exactly.
+#[derive(Default)]
+struct Index {
+    buckets: BTreeMap<i64, Vec<Totals>>,
+}
+
+impl Index {
+    fn add(&mut self, payment: &Payment) {
+        let second = payment.requested_at.div_euclid(1000) * 1000;
+        let bucket = self.buckets.entry(second).or_default();
+        let processor = payment.processor.0 as usize;
+        if bucket.len() <= processor {
+            bucket.resize(processor + 1, Totals::default());
+        }
+
+        let totals = &mut bucket[processor];
+        totals.count += 1;
+        totals.amount += payment.amount;
+    }
+
+    /// Totals indexed by processor id, sized to the highest id seen in range.
+    fn totals(&self, start_timestamp: i64, end_timestamp: i64) -> Vec<Totals> {
+        let mut totals = Vec::new();
+        if start_timestamp > end_timestamp {
+            return totals;
+        }
+
+        for bucket in self.buckets.range(start_timestamp..=end_timestamp).map(|(_, bucket)| bucket) {
+            if totals.len() < bucket.len() {
+                totals.resize(bucket.len(), Totals::default());
+            }
+            for (total, bucket_total) in totals.iter_mut().zip(bucket) {
+                total.count += bucket_total.count;
+                total.amount += bucket_total.amount;
+            }
+        }
+        totals
+    }
+}
+
+/// Sequence numbers of the last WAL entry and of the last one covered by a
+/// snapshot, and the WAL segment being appended to. Its lock also serializes
+/// WAL appends with snapshots.
+struct LogState {
+    last_seq: u64,
+    snapshot_seq: u64,
+    wal: Arc<Wal<WalEntry>>,
+    /// Entries in `wal` all come after this sequence number.
+    segment_start: u64,
+}
+
+/// Payments are logged to WAL segments, a new one being started with each
+/// snapshot. A segment is only deleted once no snapshot kept on disk needs its
+/// entries, so falling back to an older snapshot when the newest is unreadable
+/// still replays every payment recorded since.
+pub struct PaymentDb {
+    index: RwLock<Index>,
+    log: Mutex<LogState>,
+    health: RwLock<HealthCheckResult>,
+    /// Serializes snapshots, which are written outside the `log` lock.
+    snapshots: tokio::sync::Mutex<()>,
+    dir: PathBuf,
+    fsync: FsyncPolicy,
+    snapshot_retention: usize,
+}
+
+impl PaymentDb {
+    /// Opens the payment store kept in `dir`: loads the latest snapshot and
+    /// replays the write-ahead log entries recorded after it.
+    pub fn open<P: AsRef<Path>>(dir: P, fsync: FsyncPolicy, snapshot_retention: usize) -> crate::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut index = Index::default();
+        let snapshot_seq = match Snapshot::load_latest(&dir)? {
+            Some(snapshot) => {
+                index.buckets = snapshot.buckets.into_iter().collect();
+                snapshot.last_seq
+            }
+            None => 0,
+        };
+
+        let mut segments = list_segments(&dir)?;
+        if let Some(&(first, _)) = segments.first()
+            && first > snapshot_seq
+        {
+            error!(
+                "Payments #{} to #{} are missing: no snapshot covers them and their WAL segment is gone",
+                snapshot_seq + 1, first
+            );
+        }
+        if segments.is_empty() {
+            segments.push((snapshot_seq, segment_path(&dir, snapshot_seq)));
+        }
+
+        let mut last_seq = snapshot_seq;
+        let mut replayed = 0;
+        let mut active = None;
+        for (start, path) in segments {
+            let (wal, entries) = Wal::<WalEntry>::open(&path, fsync)?;
+            for entry in entries.into_iter().filter(|entry| entry.seq > snapshot_seq) {
+                last_seq = last_seq.max(entry.seq);
+                index.add(&entry.payment);
+                replayed += 1;
+            }
+            active = Some((wal, start));
+        }
+        let (wal, segment_start) = active.ok_or("No WAL segment")?;
+        info!(
+            "Loaded snapshot up to #{} and replayed {} payments up to {}",
+            snapshot_seq, replayed, wal.path().display()
+        );
+
+        Ok(Self {
+            index: RwLock::new(index),
+            log: Mutex::new(LogState { last_seq, snapshot_seq, wal: Arc::new(wal), segment_start }),
+            health: RwLock::new(HealthCheckResult::default()),
+            snapshots: tokio::sync::Mutex::new(()),
+            dir,
+            fsync,
+            snapshot_retention,
+        })
+    }
+
+    /// Records the latest health check of the processor at `index`.
+    pub async fn set_health_check(&self, index: usize, check: RecordedHealthCheck) -> crate::Result<()> {
+        let mut health = self.health.write().map_err(|_| "Failed to acquire health lock")?;
+        health.set(index, check);
+        Ok(())
+    }
+
+    pub async fn get_health_check(&self) -> crate::Result<HealthCheckResult> {
+        let health = self.health.read().map_err(|_| "Failed to acquire health lock")?;
+        Ok(health.clone())
+    }
+
+    pub async fn insert(&self, payment: Payment) -> Result<(), String> {
+        let wal = {
+            let mut log = self.log.lock().map_err(|_| "Failed to acquire log lock")?;
+            let seq = log.last_seq + 1;
+            log.wal.append(&WalEntry { seq, payment: payment.clone() })
+                .map_err(|e| format!("Failed to write payment to WAL: {}", e))?;
+            log.last_seq = seq;
+
+            // Indexed under the log lock, so a snapshot covering `seq` includes it.
+            let mut index = self.index.write().map_err(|_| "Failed to acquire index lock")?;
+            index.add(&payment);
+            log.wal.clone()
+        };
+
+        wal.commit().await
+            .map_err(|e| format!("Failed to write payment to WAL: {}", e))
+    }
+
+    /// Totals per processor id of the payments requested within the range.
+    /// Processors without payments in range may be missing from the end.
+    pub async fn get_payments_by_date_range(
+        &self,
+        start_timestamp: i64,
+        end_timestamp: i64,
+    ) -> Result<Vec<Totals>, String> {
+        let totals = self.index.read()
+            .map_err(|_| "Failed to acquire index lock")?
+            .totals(start_timestamp, end_timestamp);
+
+        Ok(totals)
+    }
+
+    /// Flushes pending WAL appends to disk.
+    pub async fn sync(&self) -> crate::Result<()> {
+        let wal = self.log.lock().map_err(|_| "Failed to acquire log lock")?.wal.clone();
+        wal.sync().await
+    }
+
+    /// Writes the current totals to a snapshot on disk, starts a new WAL
+    /// segment and prunes snapshots beyond the retention limit, along with the
+    /// segments only they needed. Inserts are only held up while the totals are
+    /// copied and the segment is swapped.
+    pub async fn snapshot(&self) -> crate::Result<()> {
+        let _snapshots = self.snapshots.lock().await;
+        let (snapshot, retired) = {
+            let mut log = self.log.lock().map_err(|_| "Failed to acquire log lock")?;
+            if log.last_seq == log.snapshot_seq {
+                return Ok(());
+            }
+
+            let buckets = self.index.read()
+                .map_err(|_| "Failed to acquire index lock")?
+                .buckets
+                .iter()
+                .map(|(&second, totals)| (second, totals.clone()))
+                .collect();
+
+            let snapshot = Snapshot {
+                last_seq: log.last_seq,
+                buckets,
+            };
+            (snapshot, self.start_segment(&mut log)?)
+        };
+
+        // Older snapshots still rely on the retired segment.
+        if let Some(wal) = retired {
+            wal.sync().await?;
+        }
+        self.write_snapshot(snapshot, self.snapshot_retention).await
+    }
+
+    /// Starts appending to a new segment after `last_seq`, unless the active
+    /// one already starts there. Returns the segment that stopped being
+    /// appended to, if any.
+    fn start_segment(&self, log: &mut LogState) -> crate::Result<Option<Arc<Wal<WalEntry>>>> {
+        if log.segment_start == log.last_seq {
+            return Ok(None);
+        }
+
+        let (wal, _) = Wal::open(segment_path(&self.dir, log.last_seq), self.fsync)?;
+        log.segment_start = log.last_seq;
+        Ok(Some(std::mem::replace(&mut log.wal, Arc::new(wal))))
+    }
+
+    /// Writes the snapshot and prunes old snapshots and segments on a blocking
+    /// thread. Callers hold `snapshots`.
+    async fn write_snapshot(&self, snapshot: Snapshot, retention: usize) -> crate::Result<()> {
+        let dir = self.dir.clone();
+        let last_seq = snapshot.last_seq;
+        let path = tokio::task::spawn_blocking(move || -> crate::Result<PathBuf> {
+            let path = snapshot.write(&dir)?;
+            if let Some(oldest) = Snapshot::prune(&dir, retention)? {
+                prune_segments(&dir, oldest)?;
+            }
+            Ok(path)
+        })
+        .await
+        .map_err(|e| format!("Snapshot task failed: {}", e))??;
+
+        let mut log = self.log.lock().map_err(|_| "Failed to acquire log lock")?;
+        log.snapshot_seq = log.snapshot_seq.max(last_seq);
+        info!("Wrote snapshot {}", path.display());
+        Ok(())
+    }
+
+    pub async fn clear(&self) -> Result<(), String> {
+        let _snapshots = self.snapshots.lock().await;
+        let snapshot = {
+            let mut log = self.log.lock().map_err(|_| "Failed to acquire log lock")?;
+            self.start_segment(&mut log).map_err(|e| format!("Failed to clear payments: {}", e))?;
+
+            let mut index = self.index.write().map_err(|_| "Failed to acquire index lock")?;
+            index.buckets.clear();
+
+            // An empty snapshot, kept alone on disk, marks everything before it as purged.
+            Snapshot {
+                last_seq: log.last_seq,
+                buckets: Vec::new(),
+            }
+        };
+
+        self.write_snapshot(snapshot, 1).await
+            .map_err(|e| format!("Failed to clear payments: {}", e))
+    }
+}
+
+fn segment_path(dir: &Path, start: u64) -> PathBuf {
+    dir.join(format!("{}{:020}{}", SEGMENT_PREFIX, start, SEGMENT_SUFFIX))
+}
+
+/// WAL segments with the sequence number their entries come after, oldest first.
+fn list_segments(dir: &Path) -> crate::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let start = path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(SEGMENT_PREFIX)?.strip_suffix(SEGMENT_SUFFIX)?.parse::<u64>().ok());
+        if let Some(start) = start {
+            segments.push((start, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+/// Deletes the segments whose entries all precede the snapshot at `oldest`,
+/// the oldest one kept. The newest segment is always kept.
+fn prune_segments(dir: &Path, oldest: u64) -> crate::Result<()> {
+    let segments = list_segments(dir)?;
+    for pair in segments.windows(2) {
+        let [(_, path), (next_start, _)] = pair else {
+            continue;
+        };
+        if *next_start <= oldest {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bincode::{Decode, Encode};
+use log::warn;
+
+use crate::money::Cents;
+
+const MAGIC: &[u8; 4] = b"MSN2";
+const PREFIX: &str = "snapshot-";
+const SUFFIX: &str = ".bin";
+
+#[derive(Debug, Clone, Copy)]
+pub struct SnapshotConfig {
+    pub interval: Duration,
+    pub retention: usize,
+}
+
+impl SnapshotConfig {
+    /// Reads `SNAPSHOT_INTERVAL_SECS` and `SNAPSHOT_RETENTION` (number of snapshot
+    /// files kept on disk, at least one).
+    pub fn from_env() -> crate::Result<SnapshotConfig> {
+        let interval = env::var("SNAPSHOT_INTERVAL_SECS")
+            .unwrap_or("30".to_string())
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid SNAPSHOT_INTERVAL_SECS: {}", e))?;
+        let retention = env::var("SNAPSHOT_RETENTION")
+            .unwrap_or("2".to_string())
+            .parse::<usize>()
+            .map_err(|e| format!("Invalid SNAPSHOT_RETENTION: {}", e))?;
+
+        Ok(SnapshotConfig {
+            interval: Duration::from_secs(interval),
+            retention: retention.max(1),
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Encode, Decode)]
+pub struct Totals {
+    pub count: u64,
+    pub amount: Cents,
+}
+
+/// Per-second totals indexed by processor id, covering every WAL entry up to
+/// and including `last_seq`.
+#[derive(Encode, Decode)]
+pub(crate) struct Snapshot {
+    pub last_seq: u64,
+    pub buckets: Vec<(i64, Vec<Totals>)>,
+}
+
+impl Snapshot {
+    /// Writes the snapshot next to the WAL, named after its sequence number so
+    /// the newest one sorts last.
+    pub fn write(&self, dir: &Path) -> crate::Result<PathBuf> {
+        let payload = bincode::encode_to_vec(self, bincode::config::standard())
+            .map_err(|e| format!("Failed to serialize snapshot: {}", e))?;
+
+        let path = dir.join(format!("{}{:020}{}", PREFIX, self.last_seq, SUFFIX));
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+
+        let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+        tmp.write_all(MAGIC)?;
+        tmp.write_all(&(payload.len() as u32).to_le_bytes())?;
+        tmp.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+        tmp.write_all(&payload)?;
+        tmp.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        std::fs::rename(&tmp_path, &path)?;
+        File::open(dir)?.sync_all()?;
+        Ok(path)
+    }
+
+    /// Loads the newest readable snapshot, skipping over corrupted ones.
+    pub fn load_latest(dir: &Path) -> crate::Result<Option<Snapshot>> {
+        for path in Self::list(dir)?.into_iter().rev() {
+            match Self::read(&path) {
+                Ok(snapshot) => return Ok(Some(snapshot)),
+                Err(e) => warn!("Ignoring snapshot {}: {}", path.display(), e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Deletes all but the `retention` newest snapshots, returning the
+    /// sequence number of the oldest one kept.
+    pub fn prune(dir: &Path, retention: usize) -> crate::Result<Option<u64>> {
+        let snapshots = Self::list(dir)?;
+        let stale = snapshots.len().saturating_sub(retention);
+        for path in &snapshots[..stale] {
+            std::fs::remove_file(path)?;
+        }
+
+        let oldest = snapshots.get(stale)
+            .and_then(|path| path.file_name()?.to_str()?.strip_prefix(PREFIX)?.strip_suffix(SUFFIX)?.parse().ok());
+        Ok(oldest)
+    }
+
+    fn read(path: &Path) -> crate::Result<Snapshot> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+
+        if data.len() < 12 || &data[0..4] != MAGIC {
+            return Err("Not a snapshot file".into());
+        }
+        let len = u32::from_le_bytes(data[4..8].try_into()?) as usize;
+        let checksum = u32::from_le_bytes(data[8..12].try_into()?);
+        let payload = data.get(12..12 + len).ok_or("Truncated snapshot")?;
+        if crc32fast::hash(payload) != checksum {
+            return Err("Snapshot checksum mismatch".into());
+        }
+
+        let (snapshot, _) = bincode::decode_from_slice(payload, bincode::config::standard())
+            .map_err(|e| format!("Failed to deserialize snapshot: {}", e))?;
+        Ok(snapshot)
+    }
+
+    fn list(dir: &Path) -> crate::Result<Vec<PathBuf>> {
+        let mut snapshots = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if name.starts_with(PREFIX) && name.ends_with(SUFFIX) {
+                snapshots.push(path);
+            }
+        }
+        // Sequence numbers are zero padded, so lexical order is sequence order.
+        snapshots.sort();
+        Ok(snapshots)
+    }
+}
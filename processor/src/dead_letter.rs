@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use bincode::{Decode, Encode};
+use log::{error, info};
+use serde::Serialize;
+
+use crate::processor::Payment;
+use crate::wal::{FsyncPolicy, Wal};
+
+/// A payment the workers gave up on, kept until an operator requeues or
+/// discards it.
+#[derive(Debug, Clone, Serialize, Encode, Decode)]
+pub struct DeadLetter {
+    pub payment: Payment,
+    #[serde(rename = "lastError")]
+    pub last_error: String,
+    pub attempts: u32,
+    #[serde(rename = "failedAt")]
+    pub failed_at: i64,
+}
+
+#[derive(Encode, Decode)]
+enum DeadLetterRecord {
+    Added(DeadLetter),
+    Removed(String),
+}
+
+/// What the log is compacted down to: the dead letters still kept.
+fn live_records(entries: &HashMap<String, DeadLetter>) -> Vec<DeadLetterRecord> {
+    entries.values().cloned().map(DeadLetterRecord::Added).collect()
+}
+
+pub struct DeadLetterQueue {
+    wal: Wal<DeadLetterRecord>,
+    entries: Mutex<HashMap<String, DeadLetter>>,
+}
+
+impl DeadLetterQueue {
+    pub fn open<P: AsRef<Path>>(wal_path: P, fsync: FsyncPolicy) -> crate::Result<Self> {
+        let (wal, records) = Wal::open(wal_path, fsync)?;
+
+        let mut entries = HashMap::new();
+        for record in records {
+            match record {
+                DeadLetterRecord::Added(letter) => {
+                    entries.insert(letter.payment.correlation_id.clone(), letter);
+                }
+                DeadLetterRecord::Removed(correlation_id) => {
+                    entries.remove(&correlation_id);
+                }
+            }
+        }
+        info!("Loaded {} dead letters from {}", entries.len(), wal.path().display());
+
+        let live = entries.len();
+        let dead_letters = DeadLetterQueue {
+            wal,
+            entries: Mutex::new(entries),
+        };
+        if dead_letters.wal.compaction_due(live) {
+            dead_letters.wal.compact(&dead_letters.entries, live_records)?;
+        }
+        Ok(dead_letters)
+    }
+
+    pub async fn add(self: &Arc<Self>, letter: DeadLetter) -> crate::Result<()> {
+        let compact = {
+            let mut entries = self.entries.lock().map_err(|_| "Failed to acquire dead letter lock")?;
+            self.wal.append(&DeadLetterRecord::Added(letter.clone()))?;
+            entries.insert(letter.payment.correlation_id.clone(), letter);
+            self.wal.compaction_due(entries.len())
+        };
+        self.wal.commit().await?;
+        self.spawn_compaction(compact);
+        Ok(())
+    }
+
+    /// Up to `limit` dead letters, oldest first.
+    pub async fn list(&self, limit: usize) -> crate::Result<Vec<DeadLetter>> {
+        let entries = self.entries.lock().map_err(|_| "Failed to acquire dead letter lock")?;
+        let mut letters: Vec<DeadLetter> = entries.values().cloned().collect();
+        letters.sort_by_key(|letter| letter.failed_at);
+        letters.truncate(limit);
+        Ok(letters)
+    }
+
+    /// Removes and returns the dead letter for `correlation_id`, if any.
+    /// Once removed letters make up most of the log, it is compacted in the
+    /// background.
+    pub async fn take(self: &Arc<Self>, correlation_id: &str) -> crate::Result<Option<DeadLetter>> {
+        let (letter, compact) = {
+            let mut entries = self.entries.lock().map_err(|_| "Failed to acquire dead letter lock")?;
+            if !entries.contains_key(correlation_id) {
+                return Ok(None);
+            }
+
+            self.wal.append(&DeadLetterRecord::Removed(correlation_id.to_string()))?;
+            let letter = entries.remove(correlation_id);
+            (letter, self.wal.compaction_due(entries.len()))
+        };
+        self.wal.commit().await?;
+        self.spawn_compaction(compact);
+        Ok(letter)
+    }
+
+    /// Flushes pending WAL appends to disk.
+    pub async fn sync(&self) -> crate::Result<()> {
+        self.wal.sync().await
+    }
+
+    pub async fn clear(&self) -> crate::Result<()> {
+        let mut entries = self.entries.lock().map_err(|_| "Failed to acquire dead letter lock")?;
+        self.wal.truncate()?;
+        entries.clear();
+        Ok(())
+    }
+
+    fn spawn_compaction(self: &Arc<Self>, due: bool) {
+        if !due {
+            return;
+        }
+        let dead_letters = self.clone();
+        tokio::task::spawn_blocking(move || {
+            dead_letters.wal.compact(&dead_letters.entries, live_records).unwrap_or_else(|e| {
+                error!("Failed to compact {}: {}", dead_letters.wal.path().display(), e);
+            });
+        });
+    }
+}
@@ -0,0 +1,322 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::circuit_breaker::{Admission, BreakerConfig, BreakerState, CircuitBreaker};
+
+/// Weight of the newest sample in the moving averages.
+const ALPHA: f64 = 0.2;
+
+/// Number of buckets the sliding window is split into.
+const WINDOW_BUCKETS: usize = 10;
+
+/// Latencies kept per endpoint for percentiles.
+const LATENCY_SAMPLES: usize = 256;
+
+/// Samples needed before percentiles are trusted.
+const MIN_PERCENTILE_SAMPLES: usize = 20;
+
+/// Latency and error rate observed on real payment requests to one endpoint,
+/// as exponentially weighted moving averages.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointStats {
+    pub latency_ms: f64,
+    pub error_rate: f64,
+    pub samples: u64,
+}
+
+impl EndpointStats {
+    fn record(&mut self, latency: Duration, success: bool) {
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        let error = if success { 0.0 } else { 1.0 };
+
+        if self.samples == 0 {
+            self.latency_ms = latency_ms;
+            self.error_rate = error;
+        } else {
+            self.latency_ms += ALPHA * (latency_ms - self.latency_ms);
+            self.error_rate += ALPHA * (error - self.error_rate);
+        }
+        self.samples += 1;
+    }
+}
+
+/// Outcomes of the payment requests made within the sliding window.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WindowStats {
+    pub requests: u64,
+    pub failures: u64,
+    pub latency_ms: f64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Bucket {
+    slot: u64,
+    requests: u64,
+    failures: u64,
+    latency_ms: f64,
+}
+
+/// Counts of recent payment outcomes, kept in fixed buckets that are reused
+/// as time moves on, so recording stays O(1) however busy the endpoint is.
+#[derive(Debug)]
+struct SlidingWindow {
+    bucket_width: Duration,
+    epoch: Instant,
+    buckets: [Bucket; WINDOW_BUCKETS],
+}
+
+impl SlidingWindow {
+    fn new(window: Duration) -> Self {
+        SlidingWindow {
+            bucket_width: (window / WINDOW_BUCKETS as u32).max(Duration::from_millis(1)),
+            epoch: Instant::now(),
+            buckets: [Bucket::default(); WINDOW_BUCKETS],
+        }
+    }
+
+    fn slot(&self, now: Instant) -> u64 {
+        (now.saturating_duration_since(self.epoch).as_nanos() / self.bucket_width.as_nanos()) as u64
+    }
+
+    fn record(&mut self, now: Instant, latency: Duration, success: bool) {
+        let slot = self.slot(now);
+        let bucket = &mut self.buckets[slot as usize % WINDOW_BUCKETS];
+        if bucket.slot != slot {
+            *bucket = Bucket { slot, ..Bucket::default() };
+        }
+
+        bucket.requests += 1;
+        if !success {
+            bucket.failures += 1;
+        }
+        bucket.latency_ms += latency.as_secs_f64() * 1000.0;
+    }
+
+    fn stats(&self, now: Instant) -> WindowStats {
+        let slot = self.slot(now);
+        let mut stats = WindowStats::default();
+        for bucket in self.buckets.iter().filter(|b| slot - b.slot < WINDOW_BUCKETS as u64) {
+            stats.requests += bucket.requests;
+            stats.failures += bucket.failures;
+            stats.latency_ms += bucket.latency_ms;
+        }
+        if stats.requests > 0 {
+            stats.latency_ms /= stats.requests as f64;
+        }
+        stats
+    }
+
+    fn clear(&mut self) {
+        self.buckets = [Bucket::default(); WINDOW_BUCKETS];
+    }
+}
+
+/// The most recent latencies of requests that got an answer. Requests that
+/// timed out count as twice the time waited for them, so timeouts derived
+/// from these widen while requests keep timing out instead of staying put.
+#[derive(Debug, Default)]
+struct LatencySamples {
+    samples: Vec<Duration>,
+    next: usize,
+}
+
+impl LatencySamples {
+    fn record(&mut self, latency: Duration) {
+        if self.samples.len() < LATENCY_SAMPLES {
+            self.samples.push(latency);
+        } else {
+            self.samples[self.next] = latency;
+        }
+        self.next = (self.next + 1) % LATENCY_SAMPLES;
+    }
+
+    fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.samples.len() < MIN_PERCENTILE_SAMPLES {
+            return None;
+        }
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+        let rank = ((sorted.len() - 1) as f64 * p.clamp(0.0, 1.0)).round() as usize;
+        Some(sorted[rank])
+    }
+}
+
+struct Endpoint {
+    name: String,
+    stats: EndpointStats,
+    window: SlidingWindow,
+    latencies: LatencySamples,
+    breaker: CircuitBreaker,
+}
+
+impl Endpoint {
+    fn poll(&mut self, config: &BreakerConfig) {
+        if let Some(state) = self.breaker.poll(config, Instant::now()) {
+            self.on_transition(state, "its cool-down is over");
+        }
+    }
+
+    fn on_transition(&mut self, state: BreakerState, reason: &str) {
+        match state {
+            BreakerState::Open => warn!("Circuit breaker for {} opened: {}", self.name, reason),
+            BreakerState::HalfOpen => info!("Circuit breaker for {} half-open: {}", self.name, reason),
+            BreakerState::Closed => {
+                // Failures from before the recovery must not reopen it right away.
+                self.window.clear();
+                info!("Circuit breaker for {} closed: {}", self.name, reason);
+            }
+        }
+    }
+}
+
+/// Circuit breaker state of one endpoint, for monitoring.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BreakerReport {
+    pub name: String,
+    pub state: BreakerState,
+    /// Unix timestamp in milliseconds of the last state change.
+    pub since: i64,
+    pub probes_in_flight: u32,
+    pub times_opened: u64,
+}
+
+/// Passive health of every configured payment processor, in priority order,
+/// built from the outcome of real payment requests.
+pub struct EndpointStatsTracker {
+    config: BreakerConfig,
+    endpoints: Mutex<Vec<Endpoint>>,
+}
+
+impl EndpointStatsTracker {
+    pub fn new(names: Vec<String>, config: BreakerConfig) -> Self {
+        let endpoints = names.into_iter()
+            .map(|name| Endpoint {
+                name,
+                stats: EndpointStats::default(),
+                window: SlidingWindow::new(config.window),
+                latencies: LatencySamples::default(),
+                breaker: CircuitBreaker::default(),
+            })
+            .collect();
+
+        EndpointStatsTracker {
+            config,
+            endpoints: Mutex::new(endpoints),
+        }
+    }
+
+    /// Feeds the outcome of a request let through by `try_admit`.
+    pub fn record(&self, index: usize, admission: Admission, latency: Duration, success: bool, timed_out: bool) {
+        let Ok(mut endpoints) = self.endpoints.lock() else {
+            return;
+        };
+        let Some(endpoint) = endpoints.get_mut(index) else {
+            return;
+        };
+
+        let now = Instant::now();
+        endpoint.stats.record(latency, success);
+        endpoint.window.record(now, latency, success);
+        if success {
+            endpoint.latencies.record(latency);
+        } else if timed_out {
+            endpoint.latencies.record(latency.saturating_mul(2));
+        }
+
+        let window = endpoint.window.stats(now);
+        let previous = endpoint.breaker.state();
+        if let Some(state) = endpoint.breaker.on_outcome(admission, success, &window, &self.config, now) {
+            let reason = match (previous, state) {
+                (BreakerState::Closed, _) => {
+                    format!("{} of the last {} payments failed", window.failures, window.requests)
+                }
+                (_, BreakerState::Open) => "a probe failed".to_string(),
+                _ => "probes succeeded".to_string(),
+            };
+            endpoint.on_transition(state, &reason);
+        }
+    }
+
+    /// Feeds an active health check, started at `checked_at`, to the breaker.
+    pub fn on_health_check(&self, index: usize, healthy: bool, checked_at: Instant) {
+        let Ok(mut endpoints) = self.endpoints.lock() else {
+            return;
+        };
+        let Some(endpoint) = endpoints.get_mut(index) else {
+            return;
+        };
+
+        if let Some(state) = endpoint.breaker.on_health_check(healthy, checked_at) {
+            endpoint.on_transition(state, "the health check reports it healthy");
+        }
+    }
+
+    /// Whether a payment may be sent to the endpoint now, taking a probe slot
+    /// if its breaker is half-open. The admission goes back with the outcome.
+    pub fn try_admit(&self, index: usize) -> Option<Admission> {
+        let mut endpoints = self.endpoints.lock().ok()?;
+        let endpoint = endpoints.get_mut(index)?;
+
+        endpoint.poll(&self.config);
+        endpoint.breaker.try_admit(&self.config)
+    }
+
+    pub fn get(&self, index: usize) -> EndpointStats {
+        self.endpoints.lock()
+            .ok()
+            .and_then(|endpoints| endpoints.get(index).map(|e| e.stats))
+            .unwrap_or_default()
+    }
+
+    pub fn window(&self, index: usize) -> WindowStats {
+        self.endpoints.lock()
+            .ok()
+            .and_then(|endpoints| endpoints.get(index).map(|e| e.window.stats(Instant::now())))
+            .unwrap_or_default()
+    }
+
+    /// The `p` percentile (0 to 1) of the latest requests' latency, as kept by
+    /// `LatencySamples`, if there have been enough of them.
+    pub fn latency_percentile(&self, index: usize, p: f64) -> Option<Duration> {
+        self.endpoints.lock()
+            .ok()
+            .and_then(|endpoints| endpoints.get(index).and_then(|e| e.latencies.percentile(p)))
+    }
+
+    pub fn breaker_state(&self, index: usize) -> BreakerState {
+        let Ok(mut endpoints) = self.endpoints.lock() else {
+            return BreakerState::Closed;
+        };
+        let Some(endpoint) = endpoints.get_mut(index) else {
+            return BreakerState::Closed;
+        };
+
+        endpoint.poll(&self.config);
+        endpoint.breaker.state()
+    }
+
+    pub fn breakers(&self) -> Vec<BreakerReport> {
+        let Ok(mut endpoints) = self.endpoints.lock() else {
+            return Vec::new();
+        };
+
+        endpoints.iter_mut()
+            .map(|endpoint| {
+                endpoint.poll(&self.config);
+                BreakerReport {
+                    name: endpoint.name.clone(),
+                    state: endpoint.breaker.state(),
+                    since: endpoint.breaker.changed_at(),
+                    probes_in_flight: endpoint.breaker.probes_in_flight(),
+                    times_opened: endpoint.breaker.times_opened(),
+                }
+            })
+            .collect()
+    }
+}
@@ -1,9 +1,20 @@
 pub use cmd::Command;
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
 
 pub mod server;
+pub mod circuit_breaker;
 pub mod db;
+pub mod dead_letter;
+pub mod endpoint_stats;
+pub mod money;
 pub mod payment_client;
+pub mod processors;
+pub mod protocol;
+pub mod queue;
+pub mod tracker;
+pub mod wal;
 
 pub mod cmd;
 pub mod processor;
@@ -11,24 +22,59 @@ pub mod workers;
 pub mod client;
 
 pub const MAX_CONNECTIONS: usize = 2048;
+/// Commands a single connection may have running at once.
+pub const MAX_REQUESTS_PER_CONNECTION: usize = 256;
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 pub type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-pub enum PaymentType {
-    Default = 0,
-    Fallback = 1,
-}
-
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
 pub struct HealthCheck {
     pub failing: bool,
     #[serde(rename = "minResponseTime")]
     pub min_response_time: u32,
 }
-#[derive(Debug, Clone)]
+
+/// A health check and when it was taken, as a Unix timestamp in milliseconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct RecordedHealthCheck {
+    #[serde(flatten)]
+    pub health: HealthCheck,
+    #[serde(rename = "checkedAt")]
+    pub checked_at: i64,
+}
+
+/// A processor's health as shared with follower processors, matched by name.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PublishedHealthCheck {
+    pub name: String,
+    #[serde(flatten)]
+    pub check: RecordedHealthCheck,
+}
+
+/// Latest health check of every configured payment processor, in priority
+/// order. Each one is recorded on its own, so a processor that cannot be
+/// checked does not hold back the others.
+#[derive(Debug, Default, Clone)]
 pub struct HealthCheckResult {
-    pub default_health_check: HealthCheck,
-    pub fallback_health_check: HealthCheck,
+    pub checks: Vec<Option<RecordedHealthCheck>>,
+}
+
+impl HealthCheckResult {
+    pub fn set(&mut self, index: usize, check: RecordedHealthCheck) {
+        if self.checks.len() <= index {
+            self.checks.resize(index + 1, None);
+        }
+        self.checks[index] = Some(check);
+    }
+
+    /// The processor's health, or `None` if it is unknown: never checked, or
+    /// last checked more than `max_age` before `now` (Unix milliseconds).
+    pub fn get(&self, index: usize, max_age: Duration, now: i64) -> Option<HealthCheck> {
+        self.checks.get(index)
+            .copied()
+            .flatten()
+            .filter(|check| now.saturating_sub(check.checked_at) <= max_age.as_millis() as i64)
+            .map(|check| check.health)
+    }
 }
\ No newline at end of file
@@ -1,16 +1,29 @@
 use std::env;
-use std::error::Error;
 use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 
 use log::info;
 use tokio::net::UnixListener;
 use moonshine_processor::cmd::App;
+use moonshine_processor::db::{PaymentDb, SnapshotConfig};
+use moonshine_processor::dead_letter::DeadLetterQueue;
+use moonshine_processor::processors::{ProcessorConfig, Processors};
+use moonshine_processor::protocol::FrameConfig;
+use moonshine_processor::queue::{PaymentQueue, QueueConfig};
+use moonshine_processor::tracker::PaymentTracker;
 use moonshine_processor::server;
-use moonshine_processor::workers::health_check_worker::health_check_worker;
+use moonshine_processor::wal::FsyncPolicy;
+use moonshine_processor::workers::health_check_worker::{health_check_worker, HealthCheckConfig};
+use moonshine_processor::workers::concurrency_limiter::ConcurrencyConfig;
+use moonshine_processor::workers::endpoint_selector::RoutingConfig;
+use moonshine_processor::workers::hedging::HedgeConfig;
 use moonshine_processor::workers::payment_worker::payment_worker;
+use moonshine_processor::workers::retry_policy::RetryPolicy;
+use moonshine_processor::workers::snapshot_worker::snapshot_worker;
+use moonshine_processor::workers::wal_sync_worker::wal_sync_worker;
 
 #[tokio::main]
-pub async fn main() -> Result<(), Box<dyn Error>> {
+pub async fn main() -> moonshine_processor::Result<()> {
     if env::var_os("RUST_LOG").is_none() {
         unsafe {
             env::set_var("RUST_LOG", "info");
@@ -19,21 +32,54 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
 
     let uds_path = env::var("UDS_PATH").unwrap_or("/tmp/moonshine-processor".to_string());
-    let payment_endpoint =
-        env::var("PAYMENT_ENDPOINT").unwrap_or("http://dev-server:8001".to_string());
-    let payment_fallback_endpoint =
-        env::var("PAYMENT_FALLBACK_ENDPOINT").unwrap_or("http://dev-server:8002".to_string());
+    let processor_configs = ProcessorConfig::from_env()?;
+    let data_dir = env::var("DATA_DIR").unwrap_or("/tmp/moonshine-data".to_string());
+    let wal_fsync = FsyncPolicy::from_env()?;
+    let snapshot_config = SnapshotConfig::from_env()?;
+    let queue_config = QueueConfig::from_env()?;
+    let retry_policy = RetryPolicy::from_env()?;
+    let routing = RoutingConfig::from_env()?;
+    let health_check_config = HealthCheckConfig::from_env()?;
+    let concurrency = ConcurrencyConfig::from_env()?;
+    let hedge_config = HedgeConfig::from_env()?;
+    let frame_config = FrameConfig::from_env()?;
+    info!("Routing payments with the {} strategy", routing.selector.name());
 
-    let app_state = App::new(payment_endpoint, payment_fallback_endpoint);
+    let processors = Processors::open(processor_configs, Path::new(&data_dir).join("processors.wal"), wal_fsync).await?;
+    let db = PaymentDb::open(&data_dir, wal_fsync, snapshot_config.retention)?;
+    let queue = PaymentQueue::open(Path::new(&data_dir).join("queue.wal"), wal_fsync, queue_config)?;
+    let tracker = PaymentTracker::open(Path::new(&data_dir).join("correlation_ids.wal"), wal_fsync)?;
+    let dead_letters = DeadLetterQueue::open(Path::new(&data_dir).join("dead_letters.wal"), wal_fsync)?;
+    let pending = queue.correlation_ids();
+    let letters = dead_letters.list(usize::MAX).await?;
+    tracker.forget_unqueued(
+        pending.iter().map(String::as_str)
+            .chain(letters.iter().map(|letter| letter.payment.correlation_id.as_str()))
+    ).await?;
+    let app_state = App::new(processors, db, queue, tracker, dead_letters, routing, concurrency);
+
+    if let FsyncPolicy::Interval(interval) = wal_fsync {
+        let wal_app = app_state.clone();
+        tokio::spawn(async move {
+            wal_sync_worker(wal_app, interval).await;
+        });
+    }
+
+    if !snapshot_config.interval.is_zero() {
+        let snapshot_app = app_state.clone();
+        tokio::spawn(async move {
+            snapshot_worker(snapshot_app, snapshot_config.interval).await;
+        });
+    }
 
     let worker_app = app_state.clone();
     tokio::spawn(async move {
-        health_check_worker(worker_app).await;
+        health_check_worker(worker_app, health_check_config, frame_config).await;
     });
 
     let payment_worker_app = app_state.clone();
     tokio::spawn(async move {
-        payment_worker(payment_worker_app).await;
+        payment_worker(payment_worker_app, retry_policy, hedge_config).await;
     });
 
     std::fs::remove_file(uds_path.clone()).ok();
@@ -45,21 +91,15 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
 
     info!("⚗️💾moonshine-processor running at {}", uds_path);
 
-    // Run server with graceful shutdown
-    tokio::select! {
-        result = server::run(listener, app_state) => {
-            match result {
-                Ok(_) => info!("✅ Server shutdown completed successfully"),
-                Err(e) => {
-                    eprintln!("❌ Server error: {}", e);
-                    std::process::exit(1);
-                }
-            }
-        }
-        _ = server::shutdown_signal() => {
-            info!("🛑 Shutdown signal received from main");
-        }
+    // Run server until a shutdown signal drains it
+    let shutdown_app = app_state.clone();
+    if let Err(e) = server::run(listener, app_state, frame_config).await {
+        eprintln!("❌ Server error: {}", e);
+        std::process::exit(1);
     }
+    info!("✅ Server shutdown completed successfully");
+
+    shutdown_app.sync().await?;
 
     info!("👋 Moonshine Processor shutdown complete");
     Ok(())
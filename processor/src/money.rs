@@ -0,0 +1,142 @@
+use std::fmt;
+use std::ops::{Add, AddAssign};
+use std::str::FromStr;
+
+use bincode::{Decode, Encode};
+use serde::de;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Money amount as an exact number of cents. Sums saturate rather than
+/// overflow, since any amount that parses may be added up any number of times.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Encode, Decode)]
+pub struct Cents(pub i64);
+
+impl Add for Cents {
+    type Output = Cents;
+
+    fn add(self, rhs: Cents) -> Cents {
+        Cents(self.0.saturating_add(rhs.0))
+    }
+}
+
+impl AddAssign for Cents {
+    fn add_assign(&mut self, rhs: Cents) {
+        *self = *self + rhs;
+    }
+}
+
+impl fmt::Display for Cents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        write!(f, "{}{}.{:02}", sign, abs / 100, abs % 100)
+    }
+}
+
+/// Parses a plain decimal such as `19.9` or `-3.05`, rejecting anything with
+/// more than two decimal places.
+impl FromStr for Cents {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cents, String> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let (units, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+
+        let valid_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if units.is_empty() || !valid_digits(units) || !valid_digits(fraction) {
+            return Err(format!("Invalid amount: {}", s));
+        }
+        if fraction.len() > 2 {
+            return Err(format!("Amount has more than two decimal places: {}", s));
+        }
+
+        let units = units.parse::<i64>().map_err(|_| format!("Amount out of range: {}", s))?;
+        let fraction = format!("{:0<2}", fraction).parse::<i64>().unwrap_or(0);
+        let cents = units
+            .checked_mul(100)
+            .and_then(|c| c.checked_add(fraction))
+            .ok_or_else(|| format!("Amount out of range: {}", s))?;
+
+        Ok(Cents(if negative { -cents } else { cents }))
+    }
+}
+
+impl Serialize for Cents {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.0 as f64 / 100.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cents {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Cents, D::Error> {
+        // With `arbitrary_precision`, the number keeps the literal the client
+        // sent, so no digit is lost to a float on the way.
+        let number = serde_json::Number::deserialize(deserializer)?;
+        number.to_string().parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_up_to_two_decimal_places() {
+        assert_eq!("19.9".parse(), Ok(Cents(1990)));
+        assert_eq!("19.90".parse(), Ok(Cents(1990)));
+        assert_eq!("20".parse(), Ok(Cents(2000)));
+        assert_eq!("0.05".parse(), Ok(Cents(5)));
+        assert_eq!("0".parse(), Ok(Cents(0)));
+    }
+
+    #[test]
+    fn parses_negative_amounts() {
+        assert_eq!("-1.5".parse(), Ok(Cents(-150)));
+        assert_eq!("-0.01".parse(), Ok(Cents(-1)));
+    }
+
+    #[test]
+    fn rejects_more_than_two_decimal_places() {
+        assert!("19.901".parse::<Cents>().is_err());
+        assert!("19.900000000000000001".parse::<Cents>().is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_amounts() {
+        for amount in ["", "-", ".5", "1e2", "1.2.3", "+1", "1,5", " 1"] {
+            assert!(amount.parse::<Cents>().is_err(), "{:?} parsed", amount);
+        }
+    }
+
+    #[test]
+    fn rejects_amounts_out_of_range() {
+        assert_eq!("92233720368547758.07".parse(), Ok(Cents(i64::MAX)));
+        assert!("92233720368547758.08".parse::<Cents>().is_err());
+        assert!("99999999999999999999".parse::<Cents>().is_err());
+    }
+
+    #[test]
+    fn deserializes_the_literal_json_number() {
+        assert_eq!(serde_json::from_str::<Cents>("19.9").unwrap(), Cents(1990));
+        assert!(serde_json::from_str::<Cents>("19.900000000000000001").is_err());
+        assert!(serde_json::from_str::<Cents>("1e2").is_err());
+    }
+
+    #[test]
+    fn sums_saturate() {
+        let max: Cents = "92233720368547758.07".parse().unwrap();
+        let mut total = max;
+        total += Cents(1);
+        assert_eq!(total, Cents(i64::MAX));
+        assert_eq!(Cents(i64::MIN) + Cents(-1), Cents(i64::MIN));
+    }
+
+    #[test]
+    fn displays_two_decimal_places() {
+        assert_eq!(Cents(1990).to_string(), "19.90");
+        assert_eq!(Cents(-5).to_string(), "-0.05");
+    }
+}
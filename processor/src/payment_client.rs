@@ -2,50 +2,80 @@ use std::time::Duration;
 use chrono::DateTime;
 use serde::{Deserialize, Serialize};
 use crate::cmd::App;
-use crate::{HealthCheck, HealthCheckResult};
+use crate::money::Cents;
+use crate::HealthCheck;
 use crate::processor::Payment;
+use crate::processors::PaymentProcessor;
 
-pub async fn health_check(app: &App) -> crate::Result<HealthCheckResult> {
-    let default_health_check = health_check_endpoint(app, &app.payment_endpoint).await?;
-    let fallback_health_check = health_check_endpoint(app, &app.payment_fallback_endpoint).await?;
+#[derive(Debug)]
+pub enum HealthCheckError {
+    /// The endpoint answered 429, with its `Retry-After` if it sent one.
+    RateLimited(Option<Duration>),
+    Failed(reqwest::Error),
+}
 
-    Ok(HealthCheckResult {
-        default_health_check,
-        fallback_health_check,
-    })
+impl std::fmt::Display for HealthCheckError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HealthCheckError::RateLimited(_) => write!(f, "rate limited"),
+            HealthCheckError::Failed(e) => write!(f, "{}", e),
+        }
+    }
 }
 
-async fn health_check_endpoint(app: &App, endpoint: &str) -> Result<HealthCheck, reqwest::Error> {
-    app.http_client
+pub async fn health_check(app: &App, endpoint: &str, timeout: Duration) -> Result<HealthCheck, HealthCheckError> {
+    let response = app.http_client
         .get(format!("{}/payments/service-health", endpoint))
+        .timeout(timeout)
         .send()
-        .await?
+        .await
+        .map_err(HealthCheckError::Failed)?;
+
+    if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = response.headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .map(Duration::from_secs);
+        return Err(HealthCheckError::RateLimited(retry_after));
+    }
+
+    response
+        .error_for_status()
+        .map_err(HealthCheckError::Failed)?
         .json::<HealthCheck>()
         .await
+        .map_err(HealthCheckError::Failed)
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PaymentDto {
     #[serde(rename = "correlationId")]
     pub correlation_id: String,
-    pub amount: f64,
+    pub amount: Cents,
     #[serde(rename = "requestedAt")]
     pub requested_at: String,
 }
 
+/// Timeout for the next payment request to the processor at `index` of
+/// `app.processors.active()`.
+pub async fn payment_timeout(app: &App, index: usize) -> Duration {
+    let processor = &app.processors.active()[index];
+    let now = chrono::Utc::now().timestamp_millis();
+    let min_response_time = app.db.get_health_check().await.ok()
+        .and_then(|health| health.get(index, app.health_max_age, now))
+        .map(|health| health.min_response_time);
+
+    processor.timeout(min_response_time, app.endpoint_stats.latency_percentile(index, 0.99))
+}
+
 pub async fn create_payment(
     app: &App,
-    endpoint: &str,
+    processor: &PaymentProcessor,
+    timeout: Duration,
     payment: &Payment,
     date: &DateTime<chrono::Utc>,
 ) -> Result<(), reqwest::Error> {
-    // TODO: Use a more sophisticated timeout strategy based on the endpoint
-    let timeout = if endpoint.contains("fallback") {
-        Duration::from_secs(10)
-    } else {
-        Duration::from_secs(10)
-    };
-
     let payment = PaymentDto {
         correlation_id: payment.correlation_id.clone(),
         amount: payment.amount,
@@ -53,7 +83,7 @@ pub async fn create_payment(
     };
 
     app.http_client
-        .post(format!("{}/payments", endpoint))
+        .post(format!("{}/payments", processor.url))
         .timeout(timeout)
         .json(&payment)
         .send()
@@ -62,9 +92,34 @@ pub async fn create_payment(
     Ok(())
 }
 
+/// Looks a payment up on the payment processor, returning `None` if it has no
+/// record of it.
+pub async fn get_payment(
+    app: &App,
+    endpoint: &str,
+    correlation_id: &str,
+) -> Result<Option<PaymentDto>, reqwest::Error> {
+    let response = app.http_client
+        .get(format!("{}/payments/{}", endpoint, correlation_id))
+        .timeout(Duration::from_secs(2))
+        .send()
+        .await?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    let payment = response
+        .error_for_status()?
+        .json::<PaymentDto>()
+        .await?;
+    Ok(Some(payment))
+}
+
 pub async fn purge(app: &App) -> Result<(), reqwest::Error> {
-    purge_endpoint(app, &app.payment_endpoint).await?;
-    purge_endpoint(app, &app.payment_fallback_endpoint).await?;
+    for processor in app.processors.active() {
+        purge_endpoint(app, &processor.url).await?;
+    }
 
     Ok(())
 }
@@ -1,9 +1,11 @@
 use bincode::{Decode, Encode};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Encode, Decode, Debug, Deserialize)]
+use crate::money::Cents;
+
+#[derive(Clone, Encode, Decode, Debug, Deserialize, Serialize)]
 pub struct Payment {
     #[serde(rename = "correlationId")]
     pub correlation_id: String,
-    pub amount: f64,
+    pub amount: Cents,
 }
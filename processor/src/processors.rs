@@ -0,0 +1,195 @@
+use std::collections::HashSet;
+use std::env;
+use std::path::Path;
+use std::time::Duration;
+
+use bincode::{Decode, Encode};
+use log::info;
+use serde::Deserialize;
+
+use crate::wal::{FsyncPolicy, Wal};
+
+/// Stable identifier of a payment processor, used wherever payments are
+/// persisted. Ids are handed out by name the first time a processor is
+/// configured and never reused, so reordering or removing processors does
+/// not reattribute stored payments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Encode, Decode)]
+pub struct ProcessorId(pub u16);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProcessorConfig {
+    pub name: String,
+    pub url: String,
+    /// Transaction fee, as a fraction of the amount.
+    pub fee: f64,
+    /// Lower is preferred.
+    #[serde(default)]
+    pub priority: u32,
+    /// Bounds of the payment request timeout.
+    #[serde(default = "default_min_timeout_ms")]
+    pub min_timeout_ms: u64,
+    #[serde(default = "default_max_timeout_ms")]
+    pub max_timeout_ms: u64,
+}
+
+fn default_min_timeout_ms() -> u64 {
+    500
+}
+
+fn default_max_timeout_ms() -> u64 {
+    10_000
+}
+
+impl ProcessorConfig {
+    /// Reads `PAYMENT_PROCESSORS`, a JSON list of processors such as
+    /// `[{"name":"default","url":"http://pp:8080","fee":0.05,"priority":0}]`,
+    /// optionally with `min_timeout_ms` and `max_timeout_ms`.
+    /// Without it, the `default` and `fallback` processors are built from
+    /// `PAYMENT_ENDPOINT`, `PAYMENT_FALLBACK_ENDPOINT`, `PAYMENT_FEE` and
+    /// `PAYMENT_FALLBACK_FEE`.
+    pub fn from_env() -> crate::Result<Vec<ProcessorConfig>> {
+        if let Ok(processors) = env::var("PAYMENT_PROCESSORS") {
+            return serde_json::from_str(&processors)
+                .map_err(|e| format!("Invalid PAYMENT_PROCESSORS: {}", e).into());
+        }
+
+        let fee = env::var("PAYMENT_FEE")
+            .unwrap_or("0.05".to_string())
+            .parse::<f64>()
+            .map_err(|e| format!("Invalid PAYMENT_FEE: {}", e))?;
+        let fallback_fee = env::var("PAYMENT_FALLBACK_FEE")
+            .unwrap_or("0.15".to_string())
+            .parse::<f64>()
+            .map_err(|e| format!("Invalid PAYMENT_FALLBACK_FEE: {}", e))?;
+
+        Ok(vec![
+            ProcessorConfig {
+                name: "default".to_string(),
+                url: env::var("PAYMENT_ENDPOINT").unwrap_or("http://dev-server:8001".to_string()),
+                fee,
+                priority: 0,
+                min_timeout_ms: default_min_timeout_ms(),
+                max_timeout_ms: default_max_timeout_ms(),
+            },
+            ProcessorConfig {
+                name: "fallback".to_string(),
+                url: env::var("PAYMENT_FALLBACK_ENDPOINT").unwrap_or("http://dev-server:8002".to_string()),
+                fee: fallback_fee,
+                priority: 1,
+                min_timeout_ms: default_min_timeout_ms(),
+                max_timeout_ms: default_max_timeout_ms(),
+            },
+        ])
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PaymentProcessor {
+    pub id: ProcessorId,
+    pub name: String,
+    pub url: String,
+    pub fee: f64,
+    pub priority: u32,
+    pub min_timeout: Duration,
+    pub max_timeout: Duration,
+}
+
+impl PaymentProcessor {
+    /// Timeout for a payment request: the observed p99 latency, or the
+    /// reported minimum response time if the processor has since become
+    /// slower than that, within the configured bounds. Until enough latencies
+    /// have been observed, the upper bound.
+    pub fn timeout(&self, min_response_time: Option<u32>, p99: Option<Duration>) -> Duration {
+        let Some(p99) = p99 else {
+            return self.max_timeout;
+        };
+        let min_response_time = Duration::from_millis(min_response_time.unwrap_or_default() as u64);
+        p99.max(min_response_time).clamp(self.min_timeout, self.max_timeout)
+    }
+}
+
+/// The configured payment processors plus the names of every processor that
+/// was ever given an id.
+pub struct Processors {
+    active: Vec<PaymentProcessor>,
+    names: Vec<String>,
+}
+
+impl Processors {
+    /// Assigns ids to newly configured processors, recording them in the
+    /// registry at `wal_path`. Processors seen before keep their id.
+    pub async fn open<P: AsRef<Path>>(
+        configs: Vec<ProcessorConfig>,
+        wal_path: P,
+        fsync: FsyncPolicy,
+    ) -> crate::Result<Self> {
+        if configs.is_empty() {
+            return Err("At least one payment processor must be configured".into());
+        }
+
+        let mut seen = HashSet::new();
+        for config in &configs {
+            if config.name.is_empty() || config.name.len() > u16::MAX as usize {
+                return Err(format!("Invalid payment processor name: {:?}", config.name).into());
+            }
+            if config.min_timeout_ms > config.max_timeout_ms {
+                return Err(format!("Invalid timeout bounds for payment processor {}", config.name).into());
+            }
+            if !seen.insert(config.name.as_str()) {
+                return Err(format!("Duplicate payment processor name: {}", config.name).into());
+            }
+        }
+
+        let (wal, mut names) = Wal::<String>::open(wal_path, fsync)?;
+        let mut active = Vec::with_capacity(configs.len());
+        for config in configs {
+            let id = match names.iter().position(|name| *name == config.name) {
+                Some(id) => id,
+                None => {
+                    wal.append(&config.name)?;
+                    names.push(config.name.clone());
+                    names.len() - 1
+                }
+            };
+            let id = u16::try_from(id).map_err(|_| "Too many payment processors")?;
+
+            active.push(PaymentProcessor {
+                id: ProcessorId(id),
+                name: config.name,
+                url: config.url,
+                fee: config.fee,
+                priority: config.priority,
+                min_timeout: Duration::from_millis(config.min_timeout_ms),
+                max_timeout: Duration::from_millis(config.max_timeout_ms),
+            });
+        }
+        wal.sync().await?;
+
+        // Stable, so processors sharing a priority keep their configured order.
+        active.sort_by_key(|processor| processor.priority);
+        for processor in &active {
+            info!(
+                "Payment processor {} (#{}) at {}, fee {}, priority {}, timeout {:?} to {:?}",
+                processor.name, processor.id.0, processor.url, processor.fee, processor.priority,
+                processor.min_timeout, processor.max_timeout
+            );
+        }
+
+        Ok(Processors { active, names })
+    }
+
+    /// The configured processors, most preferred first. Per-processor runtime
+    /// state such as health checks is kept in this order.
+    pub fn active(&self) -> &[PaymentProcessor] {
+        &self.active
+    }
+
+    /// Names of every processor ever configured, indexed by id.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn name(&self, id: ProcessorId) -> Option<&str> {
+        self.names.get(id.0 as usize).map(|name| name.as_str())
+    }
+}
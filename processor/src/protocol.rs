@@ -0,0 +1,155 @@
+use std::{env, fmt, io};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Every request is a frame of version (u8), opcode (u8), request ID (u32),
+/// payload length (u32) and payload. It is answered by a frame of request ID
+/// (u32), status (u8), payload length (u32) and payload, the payload of a
+/// response that is not `Ok` being a UTF-8 error message.
+pub const PROTOCOL_VERSION: u8 = 3;
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameConfig {
+    /// Largest payload a frame may carry. Larger frames are rejected.
+    pub max_payload_len: usize,
+}
+
+impl FrameConfig {
+    /// Reads `MAX_FRAME_BYTES`.
+    pub fn from_env() -> crate::Result<FrameConfig> {
+        let max_payload_len = env::var("MAX_FRAME_BYTES")
+            .unwrap_or("16777216".to_string())
+            .parse::<u32>()
+            .map_err(|e| format!("Invalid MAX_FRAME_BYTES: {}", e))?;
+
+        Ok(FrameConfig { max_payload_len: max_payload_len as usize })
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseStatus {
+    Ok = 0,
+    /// The request could not be decoded, or its opcode is unknown.
+    BadRequest = 1,
+    /// The command was understood but failed.
+    Failed = 2,
+}
+
+impl ResponseStatus {
+    fn from_u8(status: u8) -> Result<ResponseStatus, ProtocolError> {
+        match status {
+            0 => Ok(ResponseStatus::Ok),
+            1 => Ok(ResponseStatus::BadRequest),
+            2 => Ok(ResponseStatus::Failed),
+            _ => Err(ProtocolError::Invalid(format!("Unknown response status: {}", status))),
+        }
+    }
+}
+
+pub struct Request {
+    pub opcode: u8,
+    pub id: u32,
+    pub payload: Vec<u8>,
+}
+
+pub struct Response {
+    pub id: u32,
+    pub status: ResponseStatus,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(io::Error),
+    /// A frame that breaks the protocol. The stream cannot be trusted past it.
+    Invalid(String),
+    /// A frame whose payload is over the limit. The payload was skipped, so
+    /// the stream is still usable.
+    TooLarge { id: u32, len: usize, max: usize },
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Io(e) => write!(f, "{}", e),
+            ProtocolError::Invalid(e) => write!(f, "{}", e),
+            ProtocolError::TooLarge { len, max, .. } => {
+                write!(f, "Frame too large: {} bytes, at most {} allowed", len, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        ProtocolError::Io(e)
+    }
+}
+
+/// Reads the next request, or `None` if the peer closed the connection
+/// between two frames.
+pub async fn read_request<R: AsyncRead + Unpin>(reader: &mut R, config: FrameConfig) -> Result<Option<Request>, ProtocolError> {
+    let version = match reader.read_u8().await {
+        Ok(version) => version,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if version != PROTOCOL_VERSION {
+        return Err(ProtocolError::Invalid(format!("Unsupported protocol version: {}", version)));
+    }
+
+    let opcode = reader.read_u8().await?;
+    let id = reader.read_u32().await?;
+    let payload = read_payload(reader, id, config).await?;
+    Ok(Some(Request { opcode, id, payload }))
+}
+
+pub async fn write_request<W: AsyncWrite + Unpin>(writer: &mut W, opcode: u8, id: u32, payload: &[u8]) -> crate::Result<()> {
+    let len = payload_len(payload)?;
+    writer.write_u8(PROTOCOL_VERSION).await?;
+    writer.write_u8(opcode).await?;
+    writer.write_u32(id).await?;
+    writer.write_u32(len).await?;
+    writer.write_all(payload).await?;
+    Ok(())
+}
+
+pub async fn read_response<R: AsyncRead + Unpin>(reader: &mut R, config: FrameConfig) -> Result<Response, ProtocolError> {
+    let id = reader.read_u32().await?;
+    let status = ResponseStatus::from_u8(reader.read_u8().await?)?;
+    let payload = read_payload(reader, id, config).await?;
+    Ok(Response { id, status, payload })
+}
+
+pub async fn write_response<W: AsyncWrite + Unpin>(writer: &mut W, response: &Response) -> crate::Result<()> {
+    let len = payload_len(&response.payload)?;
+    writer.write_u32(response.id).await?;
+    writer.write_u8(response.status as u8).await?;
+    writer.write_u32(len).await?;
+    writer.write_all(&response.payload).await?;
+    Ok(())
+}
+
+async fn read_payload<R: AsyncRead + Unpin>(reader: &mut R, id: u32, config: FrameConfig) -> Result<Vec<u8>, ProtocolError> {
+    let len = reader.read_u32().await?;
+    if len as usize > config.max_payload_len {
+        // Skips the payload so the next frame can still be read.
+        let skipped = tokio::io::copy(&mut (&mut *reader).take(len as u64), &mut tokio::io::sink()).await?;
+        if skipped < len as u64 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        return Err(ProtocolError::TooLarge { id, len: len as usize, max: config.max_payload_len });
+    }
+
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).await?;
+    Ok(payload)
+}
+
+fn payload_len(payload: &[u8]) -> crate::Result<u32> {
+    u32::try_from(payload.len())
+        .map_err(|_| format!("Payload too large: {} bytes", payload.len()).into())
+}
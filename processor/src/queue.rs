@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_channel::{Receiver, Sender};
+use bincode::{Decode, Encode};
+use log::{error, info};
+use tokio::time::sleep;
+
+use crate::processor::Payment;
+use crate::processors::ProcessorId;
+use crate::wal::{FsyncPolicy, Wal};
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueueConfig {
+    /// Pending payments above which new ones are turned away.
+    pub high_water_mark: usize,
+    /// How long rejected clients are asked to wait before trying again.
+    pub retry_after: Duration,
+}
+
+impl QueueConfig {
+    /// Reads `QUEUE_HIGH_WATER_MARK` and `QUEUE_RETRY_AFTER_SECS`.
+    pub fn from_env() -> crate::Result<QueueConfig> {
+        let high_water_mark = env::var("QUEUE_HIGH_WATER_MARK")
+            .unwrap_or("50000".to_string())
+            .parse::<usize>()
+            .map_err(|e| format!("Invalid QUEUE_HIGH_WATER_MARK: {}", e))?;
+        let retry_after = env::var("QUEUE_RETRY_AFTER_SECS")
+            .unwrap_or("1".to_string())
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid QUEUE_RETRY_AFTER_SECS: {}", e))?;
+
+        Ok(QueueConfig {
+            high_water_mark,
+            retry_after: Duration::from_secs(retry_after),
+        })
+    }
+}
+
+#[derive(Encode, Decode)]
+enum QueueRecord {
+    Enqueued { id: u64, payment: Payment },
+    Acked { id: u64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct QueuedPayment {
+    pub id: u64,
+    pub payment: Payment,
+    /// Failed processing attempts so far. Not persisted, so it starts over
+    /// after a restart.
+    pub attempts: u32,
+    /// An earlier attempt timed out, so the payment may already have landed on
+    /// a payment processor.
+    pub timed_out: bool,
+    /// The payment processor and `requestedAt` a payment was accepted with,
+    /// when recording it afterwards failed. Only the recording is retried.
+    pub accepted: Option<(ProcessorId, i64)>,
+}
+
+struct QueueState {
+    next_id: u64,
+    pending: BTreeMap<u64, Payment>,
+    /// Slots handed out by `reserve` and not yet dropped.
+    reserved: usize,
+}
+
+impl QueueState {
+    /// What the log is compacted down to: the pending payments.
+    fn records(&self) -> Vec<QueueRecord> {
+        self.pending.iter()
+            .map(|(&id, payment)| QueueRecord::Enqueued { id, payment: payment.clone() })
+            .collect()
+    }
+}
+
+/// Disk-backed queue of accepted payments that still have to be sent to a
+/// payment processor. Entries stay on disk until acknowledged and are
+/// redelivered when the queue is reopened.
+///
+/// Memory is bounded by `high_water_mark`: every push needs a slot from
+/// `reserve`, which is refused once the pending payments and the slots
+/// already handed out reach it. Retries take no slot, as they never stopped
+/// being pending, and the channel never holds more than the pending payments.
+pub struct PaymentQueue {
+    wal: Wal<QueueRecord>,
+    config: QueueConfig,
+    state: Mutex<QueueState>,
+    sender: Sender<QueuedPayment>,
+    receiver: Receiver<QueuedPayment>,
+}
+
+impl PaymentQueue {
+    pub fn open<P: AsRef<Path>>(wal_path: P, fsync: FsyncPolicy, config: QueueConfig) -> crate::Result<Self> {
+        let (wal, records) = Wal::open(wal_path, fsync)?;
+
+        let mut next_id = 0;
+        let mut pending = BTreeMap::new();
+        for record in records {
+            match record {
+                QueueRecord::Enqueued { id, payment } => {
+                    next_id = next_id.max(id + 1);
+                    pending.insert(id, payment);
+                }
+                QueueRecord::Acked { id } => {
+                    pending.remove(&id);
+                }
+            }
+        }
+
+        let (sender, receiver) = async_channel::unbounded();
+        for (&id, payment) in &pending {
+            sender.try_send(QueuedPayment { id, payment: payment.clone(), attempts: 0, timed_out: false, accepted: None })
+                .map_err(|e| format!("Failed to redeliver payment: {}", e))?;
+        }
+
+        let queue = PaymentQueue {
+            wal,
+            config,
+            state: Mutex::new(QueueState { next_id, pending, reserved: 0 }),
+            sender,
+            receiver,
+        };
+
+        if queue.wal.compaction_due(queue.pending_len()) {
+            queue.wal.compact(&queue.state, QueueState::records)?;
+        }
+        info!("Redelivering {} pending payments from {}", queue.pending_len(), queue.wal.path().display());
+
+        Ok(queue)
+    }
+
+    /// Takes a slot for one payment, or `None` if the queue has reached its
+    /// high-water mark. Dropping the slot without pushing gives it back.
+    pub fn reserve(&self) -> Option<QueueSlot<'_>> {
+        let mut state = self.state.lock().ok()?;
+        if state.pending.len() + state.reserved >= self.config.high_water_mark {
+            return None;
+        }
+        state.reserved += 1;
+        Some(QueueSlot { queue: self })
+    }
+
+    /// Records the payment on disk and hands it to the workers.
+    pub async fn push(&self, _slot: QueueSlot<'_>, payment: Payment) -> crate::Result<()> {
+        let entry = {
+            let mut state = self.state.lock().map_err(|_| "Failed to acquire queue lock")?;
+            let id = state.next_id;
+            self.wal.append(&QueueRecord::Enqueued { id, payment: payment.clone() })?;
+
+            state.next_id += 1;
+            state.pending.insert(id, payment.clone());
+            QueuedPayment { id, payment, attempts: 0, timed_out: false, accepted: None }
+        };
+        self.wal.commit().await?;
+
+        self.sender.send(entry).await
+            .map_err(|e| format!("Failed to send payment to channel: {}", e))?;
+        Ok(())
+    }
+
+    /// Takes the next payment, skipping any that were cleared after they were
+    /// sent to the channel, such as retries that were sleeping at the time.
+    pub async fn recv(&self) -> crate::Result<QueuedPayment> {
+        loop {
+            let entry = self.receiver.recv().await
+                .map_err(|e| format!("Payment queue closed: {}", e))?;
+            if self.is_pending(entry.id) {
+                return Ok(entry);
+            }
+        }
+    }
+
+    /// Hands the payment back to the workers once `delay` has passed. It stays
+    /// pending on disk meanwhile.
+    pub fn retry(&self, entry: QueuedPayment, delay: Duration) {
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            sleep(delay).await;
+            if let Err(e) = sender.send(entry).await {
+                error!("Failed to requeue payment: {}", e);
+            }
+        });
+    }
+
+    /// Marks the payment as processed so it is not redelivered after a restart.
+    /// Once acked records make up most of the log, it is compacted in the
+    /// background.
+    pub async fn ack(self: &Arc<Self>, id: u64) -> crate::Result<()> {
+        let compact = {
+            let mut state = self.state.lock().map_err(|_| "Failed to acquire queue lock")?;
+            if state.pending.remove(&id).is_none() {
+                return Ok(());
+            }
+
+            self.wal.append(&QueueRecord::Acked { id })?;
+            self.wal.compaction_due(state.pending.len())
+        };
+        self.wal.commit().await?;
+
+        if compact {
+            let queue = self.clone();
+            tokio::task::spawn_blocking(move || {
+                queue.wal.compact(&queue.state, QueueState::records).unwrap_or_else(|e| {
+                    error!("Failed to compact {}: {}", queue.wal.path().display(), e);
+                });
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether the payment is still waiting to be processed, as opposed to
+    /// acked or cleared.
+    pub fn is_pending(&self, id: u64) -> bool {
+        self.state.lock()
+            .map(|state| state.pending.contains_key(&id))
+            .unwrap_or_default()
+    }
+
+    /// Drops every pending payment, including those in flight or waiting to
+    /// be retried: they are skipped when they come back around.
+    pub async fn clear(&self) -> crate::Result<()> {
+        let mut state = self.state.lock().map_err(|_| "Failed to acquire queue lock")?;
+        self.wal.truncate()?;
+        state.pending.clear();
+        while self.receiver.try_recv().is_ok() {}
+        Ok(())
+    }
+
+    pub fn retry_after(&self) -> Duration {
+        self.config.retry_after
+    }
+
+    pub fn len(&self) -> usize {
+        self.receiver.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.receiver.is_empty()
+    }
+
+    /// Flushes pending WAL appends to disk.
+    pub async fn sync(&self) -> crate::Result<()> {
+        self.wal.sync().await
+    }
+
+    /// Correlation IDs of the payments not yet processed.
+    pub fn correlation_ids(&self) -> Vec<String> {
+        self.state.lock()
+            .map(|state| state.pending.values().map(|payment| payment.correlation_id.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    fn pending_len(&self) -> usize {
+        self.state.lock().map(|state| state.pending.len()).unwrap_or_default()
+    }
+}
+
+/// Room for one payment in the queue, see `PaymentQueue::reserve`.
+pub struct QueueSlot<'a> {
+    queue: &'a PaymentQueue,
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.queue.state.lock() {
+            state.reserved -= 1;
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal;
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio::time::sleep;
+
+use crate::cmd::App;
+use crate::protocol::{self, FrameConfig, ProtocolError, Response, ResponseStatus};
+use crate::{Command, MAX_CONNECTIONS, MAX_REQUESTS_PER_CONNECTION};
+
+struct Listener {
+    listener: UnixListener,
+    app: App,
+    frame: FrameConfig,
+    limit_connections: Arc<Semaphore>,
+    notify_shutdown: broadcast::Sender<()>,
+    shutdown_complete_tx: mpsc::Sender<()>,
+}
+
+struct Handler {
+    stream: UnixStream,
+    app: App,
+    frame: FrameConfig,
+    shutdown: broadcast::Receiver<()>,
+    _shutdown_complete: mpsc::Sender<()>,
+}
+
+/// Accepts connections until a shutdown signal is received, then waits for the
+/// commands already being executed to finish before returning.
+pub async fn run(listener: UnixListener, app: App, frame: FrameConfig) -> crate::Result<()> {
+    let (notify_shutdown, _) = broadcast::channel(1);
+    let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
+
+    let mut server = Listener {
+        listener,
+        app,
+        frame,
+        limit_connections: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+        notify_shutdown,
+        shutdown_complete_tx,
+    };
+
+    tokio::select! {
+        result = server.run() => {
+            if let Err(e) = result {
+                error!("Failed to accept connection: {}", e);
+            }
+        }
+        _ = shutdown_signal() => {
+            info!("🛑 Shutdown signal received, draining connections");
+        }
+    }
+
+    let Listener {
+        notify_shutdown,
+        shutdown_complete_tx,
+        ..
+    } = server;
+
+    // Dropping the broadcast sender notifies every handler, and dropping our own
+    // completion sender leaves only the handlers' clones alive.
+    drop(notify_shutdown);
+    drop(shutdown_complete_tx);
+
+    let _ = shutdown_complete_rx.recv().await;
+    Ok(())
+}
+
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+impl Listener {
+    async fn run(&mut self) -> crate::Result<()> {
+        loop {
+            let permit = self
+                .limit_connections
+                .clone()
+                .acquire_owned()
+                .await
+                .map_err(|e| format!("Connection semaphore closed: {}", e))?;
+
+            let socket = self.accept().await?;
+
+            let handler = Handler {
+                stream: socket,
+                app: self.app.clone(),
+                frame: self.frame,
+                shutdown: self.notify_shutdown.subscribe(),
+                _shutdown_complete: self.shutdown_complete_tx.clone(),
+            };
+
+            tokio::spawn(async move {
+                if let Err(e) = handler.run().await {
+                    warn!("Connection error: {}", e);
+                }
+                drop(permit);
+            });
+        }
+    }
+
+    async fn accept(&mut self) -> crate::Result<UnixStream> {
+        let mut backoff = 1;
+
+        loop {
+            match self.listener.accept().await {
+                Ok((socket, _)) => return Ok(socket),
+                Err(e) => {
+                    if backoff > 64 {
+                        return Err(e.into());
+                    }
+                    warn!("Accept failed, retrying in {}s: {}", backoff, e);
+                }
+            }
+
+            sleep(Duration::from_secs(backoff)).await;
+            backoff *= 2;
+        }
+    }
+}
+
+impl Handler {
+    /// Reads requests and runs each in its own task, so a slow command does not
+    /// hold up the ones pipelined behind it. Responses are written in the order
+    /// the commands finish.
+    async fn run(self) -> crate::Result<()> {
+        let Handler { stream, app, frame, mut shutdown, _shutdown_complete: shutdown_complete } = self;
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        let (responses, pending_responses) = mpsc::channel(MAX_REQUESTS_PER_CONNECTION);
+        let writer = tokio::spawn(write_responses(BufWriter::new(writer), pending_responses));
+        let limit_requests = Arc::new(Semaphore::new(MAX_REQUESTS_PER_CONNECTION));
+
+        let read = loop {
+            let permit = limit_requests
+                .clone()
+                .acquire_owned()
+                .await
+                .map_err(|e| format!("Request semaphore closed: {}", e))?;
+
+            let result = tokio::select! {
+                res = protocol::read_request(&mut reader, frame) => res,
+                _ = shutdown.recv() => break Ok(()),
+            };
+
+            match result {
+                Ok(Some(request)) => {
+                    let app = app.clone();
+                    let responses = responses.clone();
+                    let shutdown_complete = shutdown_complete.clone();
+                    // Once a request has been read the command runs to completion, even if
+                    // a shutdown arrives meanwhile, so the client never sees a partial reply.
+                    tokio::spawn(async move {
+                        let response = execute(request, &app, frame).await;
+                        let _ = responses.send(response).await;
+                        drop((permit, shutdown_complete));
+                    });
+                }
+                Ok(None) => {
+                    debug!("Client disconnected");
+                    break Ok(());
+                }
+                Err(e @ ProtocolError::TooLarge { id, .. }) => {
+                    warn!("Bad request {}: {}", id, e);
+                    let response = error_response(id, ResponseStatus::BadRequest, e.into());
+                    if responses.send(response).await.is_err() {
+                        break Ok(());
+                    }
+                }
+                Err(e) => break Err(e),
+            }
+        };
+
+        // The writer stops once every command already started has answered.
+        drop(responses);
+        let written = writer.await.map_err(|e| format!("Response writer failed: {}", e))?;
+        read?;
+        written
+    }
+}
+
+/// Writes responses as commands finish, flushing once none are left waiting.
+async fn write_responses(mut writer: BufWriter<OwnedWriteHalf>, mut responses: mpsc::Receiver<Response>) -> crate::Result<()> {
+    while let Some(response) = responses.recv().await {
+        protocol::write_response(&mut writer, &response).await?;
+        while let Ok(response) = responses.try_recv() {
+            protocol::write_response(&mut writer, &response).await?;
+        }
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+/// Runs the command in `request`. Errors are reported back in the response,
+/// leaving the connection usable.
+async fn execute(request: protocol::Request, app: &App, frame: FrameConfig) -> Response {
+    let cmd = match Command::from_data(request.opcode, &request.payload) {
+        Ok(cmd) => cmd,
+        Err(e) => {
+            warn!("Bad request {}: {}", request.id, e);
+            return error_response(request.id, ResponseStatus::BadRequest, e);
+        }
+    };
+
+    let mut payload = Vec::new();
+    match cmd.execute(&mut payload, app).await {
+        Ok(()) if payload.len() > frame.max_payload_len => {
+            let e = format!("Response too large: {} bytes, at most {} allowed", payload.len(), frame.max_payload_len);
+            error!("Request {} failed: {}", request.id, e);
+            error_response(request.id, ResponseStatus::Failed, e.into())
+        }
+        Ok(()) => Response { id: request.id, status: ResponseStatus::Ok, payload },
+        Err(e) => {
+            error!("Request {} failed: {}", request.id, e);
+            error_response(request.id, ResponseStatus::Failed, e)
+        }
+    }
+}
+
+fn error_response(id: u32, status: ResponseStatus, error: crate::Error) -> Response {
+    Response { id, status, payload: error.to_string().into_bytes() }
+}
@@ -0,0 +1,313 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use bincode::{Decode, Encode};
+use log::{error, info};
+use serde::Serialize;
+
+use crate::processors::ProcessorId;
+use crate::wal::{FsyncPolicy, Wal};
+
+/// Where a payment is in its lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentState {
+    Queued = 0,
+    InFlight = 1,
+    Succeeded = 2,
+    /// The payment processor already knew the correlation ID.
+    Duplicate = 3,
+    FailedPermanently = 4,
+}
+
+impl PaymentState {
+    pub fn from_u8(state: u8) -> crate::Result<PaymentState> {
+        match state {
+            0 => Ok(PaymentState::Queued),
+            1 => Ok(PaymentState::InFlight),
+            2 => Ok(PaymentState::Succeeded),
+            3 => Ok(PaymentState::Duplicate),
+            4 => Ok(PaymentState::FailedPermanently),
+            _ => Err(format!("Unknown payment state: {}", state).into()),
+        }
+    }
+}
+
+/// A payment's state and, once it succeeded, the processor that took it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaymentStatus {
+    pub state: PaymentState,
+    pub processor: Option<ProcessorId>,
+}
+
+impl PaymentStatus {
+    pub fn succeeded(processor: ProcessorId) -> PaymentStatus {
+        PaymentStatus {
+            state: PaymentState::Succeeded,
+            processor: Some(processor),
+        }
+    }
+
+    /// The status as it is written to the log. In flight is never persisted:
+    /// after a restart the payment is redelivered, so it really is queued again.
+    fn persisted(self) -> PaymentStatus {
+        if self.state == PaymentState::InFlight { PaymentState::Queued.into() } else { self }
+    }
+}
+
+impl From<PaymentState> for PaymentStatus {
+    fn from(state: PaymentState) -> Self {
+        PaymentStatus { state, processor: None }
+    }
+}
+
+/// How a `PaymentStatus` is written to the log.
+#[derive(Encode, Decode)]
+enum StoredStatus {
+    Queued,
+    InFlight,
+    Succeeded(Option<ProcessorId>),
+    Duplicate,
+    FailedPermanently,
+}
+
+impl From<PaymentStatus> for StoredStatus {
+    fn from(status: PaymentStatus) -> Self {
+        match status.state {
+            PaymentState::Queued => StoredStatus::Queued,
+            PaymentState::InFlight => StoredStatus::InFlight,
+            PaymentState::Succeeded => StoredStatus::Succeeded(status.processor),
+            PaymentState::Duplicate => StoredStatus::Duplicate,
+            PaymentState::FailedPermanently => StoredStatus::FailedPermanently,
+        }
+    }
+}
+
+impl From<StoredStatus> for PaymentStatus {
+    fn from(status: StoredStatus) -> Self {
+        match status {
+            StoredStatus::Queued => PaymentState::Queued.into(),
+            StoredStatus::InFlight => PaymentState::InFlight.into(),
+            StoredStatus::Succeeded(processor) => PaymentStatus { state: PaymentState::Succeeded, processor },
+            StoredStatus::Duplicate => PaymentState::Duplicate.into(),
+            StoredStatus::FailedPermanently => PaymentState::FailedPermanently.into(),
+        }
+    }
+}
+
+/// Correlation IDs are almost always canonical UUIDs, which are kept as a
+/// plain `u128` instead of a heap allocated string.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Encode, Decode)]
+pub enum CorrelationKey {
+    Uuid(u128),
+    Other(String),
+}
+
+impl CorrelationKey {
+    pub fn new(correlation_id: &str) -> CorrelationKey {
+        Self::parse_uuid(correlation_id)
+            .map(CorrelationKey::Uuid)
+            .unwrap_or_else(|| CorrelationKey::Other(correlation_id.to_string()))
+    }
+
+    /// Only the lowercase hyphenated form maps to `Uuid`, so two different
+    /// strings never share a key.
+    fn parse_uuid(s: &str) -> Option<u128> {
+        let bytes = s.as_bytes();
+        if bytes.len() != 36 {
+            return None;
+        }
+
+        let mut value = 0u128;
+        for (i, &b) in bytes.iter().enumerate() {
+            if matches!(i, 8 | 13 | 18 | 23) {
+                if b != b'-' {
+                    return None;
+                }
+                continue;
+            }
+            let digit = match b {
+                b'0'..=b'9' => b - b'0',
+                b'a'..=b'f' => b - b'a' + 10,
+                _ => return None,
+            };
+            value = (value << 4) | digit as u128;
+        }
+        Some(value)
+    }
+}
+
+#[derive(Encode, Decode)]
+enum TrackerRecord {
+    State(CorrelationKey, StoredStatus),
+    Forgotten(CorrelationKey),
+}
+
+struct TrackerState {
+    statuses: HashMap<CorrelationKey, PaymentStatus>,
+}
+
+impl TrackerState {
+    /// What the log is compacted down to: the latest status of each
+    /// correlation ID.
+    fn records(&self) -> Vec<TrackerRecord> {
+        self.statuses.iter()
+            .map(|(key, status)| TrackerRecord::State(key.clone(), status.persisted().into()))
+            .collect()
+    }
+}
+
+/// Tracks the state of every payment accepted since the last purge, so
+/// duplicates are rejected before they cost a call to the payment processors
+/// and support can look a payment up by correlation ID.
+pub struct PaymentTracker {
+    wal: Wal<TrackerRecord>,
+    state: Mutex<TrackerState>,
+}
+
+impl PaymentTracker {
+    pub fn open<P: AsRef<Path>>(wal_path: P, fsync: FsyncPolicy) -> crate::Result<Self> {
+        let (wal, records) = Wal::open(wal_path, fsync)?;
+
+        let mut statuses = HashMap::with_capacity(records.len());
+        for record in records {
+            match record {
+                TrackerRecord::State(key, status) => statuses.insert(key, status.into()),
+                TrackerRecord::Forgotten(key) => statuses.remove(&key),
+            };
+        }
+        info!("Loaded {} correlation IDs from {}", statuses.len(), wal.path().display());
+
+        let tracker = PaymentTracker {
+            wal,
+            state: Mutex::new(TrackerState { statuses }),
+        };
+        if tracker.wal.compaction_due(tracker.len()) {
+            tracker.wal.compact(&tracker.state, TrackerState::records)?;
+        }
+
+        Ok(tracker)
+    }
+
+    /// Forgets queued correlation IDs that are not among `queued`, the
+    /// payments still pending or dead-lettered. A crash after a payment was
+    /// tracked but before it was queued leaves its ID behind, which would turn
+    /// the client's retry away as a duplicate.
+    pub async fn forget_unqueued<'a>(&self, queued: impl IntoIterator<Item = &'a str>) -> crate::Result<()> {
+        let queued: HashSet<CorrelationKey> = queued.into_iter().map(CorrelationKey::new).collect();
+        let forgotten = {
+            let mut state = self.state.lock().map_err(|_| "Failed to acquire tracker lock")?;
+            let orphans: Vec<CorrelationKey> = state.statuses.iter()
+                .filter(|(key, status)| {
+                    matches!(status.state, PaymentState::Queued | PaymentState::InFlight) && !queued.contains(key)
+                })
+                .map(|(key, _)| key.clone())
+                .collect();
+
+            for key in &orphans {
+                self.wal.append(&TrackerRecord::Forgotten(key.clone()))?;
+                state.statuses.remove(key);
+            }
+            orphans.len()
+        };
+        if forgotten > 0 {
+            self.wal.commit().await?;
+            info!("Forgot {} correlation IDs whose payments were never queued", forgotten);
+        }
+        Ok(())
+    }
+
+    /// Records the correlation ID as queued, returning `false` if it was
+    /// already known.
+    pub async fn insert(self: &Arc<Self>, correlation_id: &str) -> crate::Result<bool> {
+        let key = CorrelationKey::new(correlation_id);
+        let compact = {
+            let mut state = self.state.lock().map_err(|_| "Failed to acquire tracker lock")?;
+            if state.statuses.contains_key(&key) {
+                return Ok(false);
+            }
+
+            let status = PaymentState::Queued.into();
+            self.wal.append(&TrackerRecord::State(key.clone(), StoredStatus::from(status)))?;
+            state.statuses.insert(key, status);
+            self.wal.compaction_due(state.statuses.len())
+        };
+        self.wal.commit().await?;
+        self.spawn_compaction(compact);
+        Ok(true)
+    }
+
+    /// Moves a payment to a new state. The log is only written when the
+    /// persisted status changes, see `PaymentStatus::persisted`.
+    pub async fn set_state(self: &Arc<Self>, correlation_id: &str, status: impl Into<PaymentStatus>) -> crate::Result<()> {
+        let status = status.into();
+        let key = CorrelationKey::new(correlation_id);
+        let (changed, compact) = {
+            let mut state = self.state.lock().map_err(|_| "Failed to acquire tracker lock")?;
+            let previous = state.statuses.insert(key.clone(), status);
+            let changed = previous.map(PaymentStatus::persisted) != Some(status.persisted());
+            if changed {
+                self.wal.append(&TrackerRecord::State(key, status.persisted().into()))?;
+            }
+            (changed, changed && self.wal.compaction_due(state.statuses.len()))
+        };
+        if changed {
+            self.wal.commit().await?;
+        }
+        self.spawn_compaction(compact);
+        Ok(())
+    }
+
+    pub async fn get(&self, correlation_id: &str) -> crate::Result<Option<PaymentStatus>> {
+        let key = CorrelationKey::new(correlation_id);
+        let state = self.state.lock().map_err(|_| "Failed to acquire tracker lock")?;
+        Ok(state.statuses.get(&key).copied())
+    }
+
+    /// Drops a correlation ID whose payment could not be accepted after all.
+    pub async fn forget(self: &Arc<Self>, correlation_id: &str) -> crate::Result<()> {
+        let key = CorrelationKey::new(correlation_id);
+        let compact = {
+            let mut state = self.state.lock().map_err(|_| "Failed to acquire tracker lock")?;
+            if !state.statuses.contains_key(&key) {
+                return Ok(());
+            }
+
+            self.wal.append(&TrackerRecord::Forgotten(key.clone()))?;
+            state.statuses.remove(&key);
+            self.wal.compaction_due(state.statuses.len())
+        };
+        self.wal.commit().await?;
+        self.spawn_compaction(compact);
+        Ok(())
+    }
+
+    /// Flushes pending WAL appends to disk.
+    pub async fn sync(&self) -> crate::Result<()> {
+        self.wal.sync().await
+    }
+
+    pub async fn clear(&self) -> crate::Result<()> {
+        let mut state = self.state.lock().map_err(|_| "Failed to acquire tracker lock")?;
+        self.wal.truncate()?;
+        state.statuses.clear();
+        Ok(())
+    }
+
+    fn spawn_compaction(self: &Arc<Self>, due: bool) {
+        if !due {
+            return;
+        }
+        let tracker = self.clone();
+        tokio::task::spawn_blocking(move || {
+            tracker.wal.compact(&tracker.state, TrackerState::records).unwrap_or_else(|e| {
+                error!("Failed to compact {}: {}", tracker.wal.path().display(), e);
+            });
+        });
+    }
+
+    fn len(&self) -> usize {
+        self.state.lock().map(|state| state.statuses.len()).unwrap_or_default()
+    }
+}
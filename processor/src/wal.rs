@@ -0,0 +1,398 @@
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use bincode::{Decode, Encode};
+use log::warn;
+
+/// Each record is framed as `[len: u32][crc32: u32][payload]`, little endian.
+const HEADER_SIZE: usize = 8;
+
+/// Superseded records are only dropped from disk once a log holds at least this many.
+const COMPACTION_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// fsync after every append.
+    Always,
+    /// Appends are flushed to the OS immediately and fsynced by `wal_sync_worker`.
+    /// Never zero.
+    Interval(Duration),
+    /// Leave it to the OS.
+    Never,
+}
+
+impl FsyncPolicy {
+    /// Reads `WAL_FSYNC` (`always`, `interval` or `never`) and `WAL_FSYNC_INTERVAL_MS`.
+    pub fn from_env() -> crate::Result<FsyncPolicy> {
+        let policy = env::var("WAL_FSYNC").unwrap_or("interval".to_string());
+        match policy.as_str() {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            "interval" => {
+                let millis = env::var("WAL_FSYNC_INTERVAL_MS")
+                    .unwrap_or("100".to_string())
+                    .parse::<u64>()
+                    .map_err(|e| format!("Invalid WAL_FSYNC_INTERVAL_MS: {}", e))?;
+                if millis == 0 {
+                    return Err("Invalid WAL_FSYNC_INTERVAL_MS: must be greater than 0".into());
+                }
+                Ok(FsyncPolicy::Interval(Duration::from_millis(millis)))
+            }
+            _ => Err(format!("Invalid WAL_FSYNC: {}", policy).into()),
+        }
+    }
+}
+
+/// Append-only log of bincode encoded records, each protected by a checksum.
+pub struct Wal<T> {
+    path: PathBuf,
+    file: Mutex<LogFile>,
+    fsync: FsyncPolicy,
+    dirty: AtomicBool,
+    _record: PhantomData<fn(T)>,
+}
+
+struct LogFile {
+    file: File,
+    /// Records in the log, live or superseded.
+    records: usize,
+    /// A compaction was claimed by `compaction_due` and has not finished.
+    compacting: bool,
+    /// Frames appended since a rewrite began, to carry over into the new log.
+    carried: Option<Vec<u8>>,
+    /// `records` when the rewrite began.
+    rewritten: usize,
+}
+
+impl<T: Encode + Decode<()>> Wal<T> {
+    /// Opens (or creates) the log at `path` and returns it with every valid record
+    /// found on disk. A torn or corrupted tail is cut off so new appends start
+    /// from the last good record.
+    pub fn open<P: AsRef<Path>>(path: P, fsync: FsyncPolicy) -> crate::Result<(Wal<T>, Vec<T>)> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        let (records, valid_len) = Self::replay(&mut file)?;
+        let file_len = file.metadata()?.len();
+        if valid_len < file_len {
+            warn!(
+                "Truncating {} bytes of invalid data at the end of {}",
+                file_len - valid_len,
+                path.display()
+            );
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+
+        let wal = Wal {
+            path,
+            file: Mutex::new(LogFile { file, records: records.len(), compacting: false, carried: None, rewritten: 0 }),
+            fsync,
+            dirty: AtomicBool::new(false),
+            _record: PhantomData,
+        };
+        Ok((wal, records))
+    }
+
+    fn replay(file: &mut File) -> crate::Result<(Vec<T>, u64)> {
+        let file_len = file.metadata()?.len();
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(file);
+        let mut records = Vec::new();
+        let mut valid_len = 0u64;
+        let mut header = [0u8; HEADER_SIZE];
+
+        loop {
+            match reader.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+
+            let len = u32::from_le_bytes(header[0..4].try_into()?) as usize;
+            let checksum = u32::from_le_bytes(header[4..8].try_into()?);
+
+            // A length running past the end of the file is a torn or corrupted
+            // header, not a reason to allocate that much.
+            if valid_len + (HEADER_SIZE + len) as u64 > file_len {
+                break;
+            }
+
+            let mut payload = vec![0; len];
+            match reader.read_exact(&mut payload) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+
+            if crc32fast::hash(&payload) != checksum {
+                break;
+            }
+
+            let Ok((record, _)) = bincode::decode_from_slice(&payload, bincode::config::standard()) else {
+                break;
+            };
+
+            records.push(record);
+            valid_len += (HEADER_SIZE + len) as u64;
+        }
+
+        Ok((records, valid_len))
+    }
+
+    fn encode_frame(record: &T) -> crate::Result<Vec<u8>> {
+        let payload = bincode::encode_to_vec(record, bincode::config::standard())
+            .map_err(|e| format!("Failed to serialize WAL record: {}", e))?;
+
+        let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    /// Writes the record to the OS. Unless the policy is `Never`, callers must
+    /// then await `commit` before reporting the record as persisted; it is
+    /// kept separate so callers can release their own locks first.
+    pub fn append(&self, record: &T) -> crate::Result<()> {
+        let frame = Self::encode_frame(record)?;
+
+        let mut log = self.file.lock().map_err(|_| "Failed to acquire WAL lock")?;
+        log.file.write_all(&frame)?;
+        log.records += 1;
+        if let Some(carried) = log.carried.as_mut() {
+            carried.extend_from_slice(&frame);
+        }
+
+        if self.fsync != FsyncPolicy::Never {
+            self.dirty.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Waits until the records appended so far are as durable as the fsync
+    /// policy requires: on disk with `Always`, handed to the OS otherwise.
+    pub async fn commit(&self) -> crate::Result<()> {
+        match self.fsync {
+            // Another task may be syncing already, but that sync may have
+            // started before our append, so this one always runs.
+            FsyncPolicy::Always => self.sync_file().await,
+            FsyncPolicy::Interval(_) | FsyncPolicy::Never => Ok(()),
+        }
+    }
+
+    /// Fsyncs the log if anything was appended since the last sync.
+    pub async fn sync(&self) -> crate::Result<()> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        self.sync_file().await
+    }
+
+    /// Fsyncs on a blocking thread, through its own handle so appends need
+    /// not wait for it.
+    async fn sync_file(&self) -> crate::Result<()> {
+        let file = self.file.lock().map_err(|_| "Failed to acquire WAL lock")?.file.try_clone()?;
+        tokio::task::spawn_blocking(move || file.sync_data())
+            .await
+            .map_err(|e| format!("WAL sync task failed: {}", e))??;
+        Ok(())
+    }
+
+    /// Whether the log is due for compaction, given that `live` of its records
+    /// would be kept: it holds at least `COMPACTION_THRESHOLD` records and at
+    /// least half of them are superseded. If so, the compaction is claimed and
+    /// the caller must run `compact`.
+    pub fn compaction_due(&self, live: usize) -> bool {
+        let Ok(mut log) = self.file.lock() else {
+            return false;
+        };
+        let due = !log.compacting
+            && log.records >= COMPACTION_THRESHOLD
+            && log.records >= 2 * live;
+        log.compacting |= due;
+        due
+    }
+
+    /// Rewrites the log with the records `snapshot` builds from the owner's
+    /// `state`. The snapshot is taken under the owner's lock, which must also
+    /// be held around its appends, so records appended meanwhile are carried
+    /// over rather than lost. Blocks on file I/O, so it runs on a blocking
+    /// thread; `compaction_due` must have claimed it.
+    pub fn compact<S>(&self, state: &Mutex<S>, snapshot: impl FnOnce(&S) -> Vec<T>) -> crate::Result<()> {
+        let records = match state.lock() {
+            Ok(state) => self.begin_rewrite().map(|()| snapshot(&state)),
+            Err(_) => Err("Failed to acquire lock on the log's owner".into()),
+        };
+        let result = records.and_then(|records| self.finish_rewrite(&records).map(|done| (done, records.len())));
+
+        let mut log = self.file.lock().map_err(|_| "Failed to acquire WAL lock")?;
+        log.compacting = false;
+        let (done, kept) = result?;
+        if done {
+            // Records appended during the rewrite were carried over.
+            log.records = log.records.saturating_sub(log.rewritten) + kept;
+        }
+        Ok(())
+    }
+
+    /// Starts rewriting the log: records appended from now on are carried over
+    /// into the one written by `finish_rewrite`.
+    fn begin_rewrite(&self) -> crate::Result<()> {
+        let mut log = self.file.lock().map_err(|_| "Failed to acquire WAL lock")?;
+        log.carried = Some(Vec::new());
+        log.rewritten = log.records;
+        Ok(())
+    }
+
+    /// Atomically replaces the whole log with `records`, followed by whatever
+    /// was appended since `begin_rewrite`. Appends are only held up while the
+    /// carried records are copied over. A truncation in between cancels the
+    /// rewrite, in which case `false` is returned.
+    fn finish_rewrite(&self, records: &[T]) -> crate::Result<bool> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".compact");
+
+        let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+        for record in records {
+            tmp.write_all(&Self::encode_frame(record)?)?;
+        }
+        let mut tmp = tmp.into_inner().map_err(|e| e.into_error())?;
+        tmp.sync_data()?;
+
+        let mut log = self.file.lock().map_err(|_| "Failed to acquire WAL lock")?;
+        let Some(carried) = log.carried.take() else {
+            std::fs::remove_file(&tmp_path)?;
+            return Ok(false);
+        };
+        tmp.write_all(&carried)?;
+        tmp.sync_all()?;
+
+        std::fs::rename(&tmp_path, &self.path)?;
+        let dir = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        File::open(dir)?.sync_all()?;
+
+        log.file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+        self.dirty.store(false, Ordering::Release);
+        Ok(true)
+    }
+
+    /// Drops every record in the log.
+    pub fn truncate(&self) -> crate::Result<()> {
+        let mut log = self.file.lock().map_err(|_| "Failed to acquire WAL lock")?;
+        log.file.set_len(0)?;
+        log.file.sync_all()?;
+        log.records = 0;
+        log.carried = None;
+        self.dirty.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_wal(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("moonshine-wal-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.join("test.wal")
+    }
+
+    fn write_records(path: &Path, records: &[u64]) -> u64 {
+        let (wal, _) = Wal::<u64>::open(path, FsyncPolicy::Never).unwrap();
+        for record in records {
+            wal.append(record).unwrap();
+        }
+        std::fs::metadata(path).unwrap().len()
+    }
+
+    fn reopen(path: &Path) -> Vec<u64> {
+        Wal::<u64>::open(path, FsyncPolicy::Never).unwrap().1
+    }
+
+    #[test]
+    fn replays_appended_records() {
+        let path = temp_wal("replay");
+        write_records(&path, &[1, 2, 3]);
+        assert_eq!(reopen(&path), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn cuts_off_a_torn_tail() {
+        let path = temp_wal("torn");
+        let first = write_records(&path, &[1]);
+        let len = write_records(&path, &[2]);
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 1).unwrap();
+
+        assert_eq!(reopen(&path), vec![1]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), first);
+    }
+
+    #[test]
+    fn stops_at_a_bad_checksum() {
+        let path = temp_wal("crc");
+        let first = write_records(&path, &[1]);
+        write_records(&path, &[2, 3]);
+
+        let mut data = std::fs::read(&path).unwrap();
+        data[first as usize + HEADER_SIZE] ^= 0xFF;
+        std::fs::write(&path, data).unwrap();
+
+        assert_eq!(reopen(&path), vec![1]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), first);
+    }
+
+    #[test]
+    fn treats_an_oversize_length_as_a_torn_tail() {
+        let path = temp_wal("oversize");
+        let first = write_records(&path, &[1]);
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        file.write_all(&0u32.to_le_bytes()).unwrap();
+        file.write_all(&[0; 16]).unwrap();
+        drop(file);
+
+        assert_eq!(reopen(&path), vec![1]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), first);
+    }
+
+    #[test]
+    fn compacts_once_most_records_are_superseded() {
+        let path = temp_wal("compact");
+        let (wal, _) = Wal::<u64>::open(&path, FsyncPolicy::Never).unwrap();
+        for record in 0..COMPACTION_THRESHOLD as u64 - 1 {
+            wal.append(&record).unwrap();
+        }
+        assert!(!wal.compaction_due(1));
+        wal.append(&0).unwrap();
+        assert!(!wal.compaction_due(COMPACTION_THRESHOLD));
+        assert!(wal.compaction_due(1));
+        assert!(!wal.compaction_due(1), "claimed twice");
+
+        let state = Mutex::new(vec![7u64, 8]);
+        wal.compact(&state, |records| records.clone()).unwrap();
+        wal.append(&9).unwrap();
+        assert!(!wal.compaction_due(0));
+        assert_eq!(reopen(&path), vec![7, 8, 9]);
+    }
+}
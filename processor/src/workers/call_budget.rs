@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use tokio::time::{sleep_until, Instant};
+
+/// Paces calls to a rate-limited endpoint: at most one call per `interval`,
+/// pushed further out while the endpoint answers 429.
+#[derive(Debug, Clone)]
+pub struct CallBudget {
+    interval: Duration,
+    max_backoff: Duration,
+    backoff: Duration,
+    next_call: Instant,
+}
+
+impl CallBudget {
+    pub fn new(interval: Duration, max_backoff: Duration) -> Self {
+        CallBudget {
+            interval,
+            max_backoff: max_backoff.max(interval),
+            backoff: interval,
+            next_call: Instant::now(),
+        }
+    }
+
+    /// Waits for the next call allowed and books it.
+    pub async fn acquire(&mut self) {
+        sleep_until(self.next_call).await;
+        self.next_call = Instant::now() + self.interval;
+    }
+
+    pub fn on_success(&mut self) {
+        self.backoff = self.interval;
+    }
+
+    /// Honours the server's `Retry-After` if it sent one, otherwise doubles
+    /// the wait up to `max_backoff`. Returns how long calls are held off.
+    pub fn on_rate_limited(&mut self, retry_after: Option<Duration>) -> Duration {
+        self.backoff = (self.backoff * 2).min(self.max_backoff);
+        let delay = retry_after.unwrap_or(self.backoff).max(self.interval);
+        self.next_call = Instant::now() + delay;
+        delay
+    }
+}
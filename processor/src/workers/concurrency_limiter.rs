@@ -0,0 +1,194 @@
+use std::env;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use tokio::sync::Notify;
+
+/// How often the no-load latency estimate starts over, so it follows the
+/// processors when they get permanently slower.
+const BASELINE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Weight of the newest sample in the recent latency average.
+const ALPHA: f64 = 0.2;
+
+/// Latency increases smaller than this are noise rather than queueing.
+const LATENCY_SLACK: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConcurrencyConfig {
+    pub min: usize,
+    pub max: usize,
+    /// Limit to start from.
+    pub initial: usize,
+    /// How many times the no-load latency a request may take before the
+    /// processors are considered overloaded.
+    pub latency_tolerance: f64,
+    /// Factor the limit is multiplied by on overload.
+    pub decrease_factor: f64,
+}
+
+impl ConcurrencyConfig {
+    /// Reads `CONCURRENCY_MIN`, `CONCURRENCY_MAX`, `CONCURRENCY_INITIAL`,
+    /// `CONCURRENCY_LATENCY_TOLERANCE` and `CONCURRENCY_DECREASE_FACTOR`.
+    pub fn from_env() -> crate::Result<ConcurrencyConfig> {
+        fn parse<T: FromStr>(name: &str, default: &str) -> crate::Result<T>
+        where
+            T::Err: Display,
+        {
+            env::var(name)
+                .unwrap_or(default.to_string())
+                .parse::<T>()
+                .map_err(|e| format!("Invalid {}: {}", name, e).into())
+        }
+
+        let min = parse::<usize>("CONCURRENCY_MIN", "1")?.max(1);
+        let max = parse::<usize>("CONCURRENCY_MAX", "64")?.max(min);
+        Ok(ConcurrencyConfig {
+            min,
+            max,
+            initial: parse::<usize>("CONCURRENCY_INITIAL", "3")?.clamp(min, max),
+            latency_tolerance: parse::<f64>("CONCURRENCY_LATENCY_TOLERANCE", "2.0")?.max(1.0),
+            decrease_factor: parse::<f64>("CONCURRENCY_DECREASE_FACTOR", "0.9")?.clamp(0.1, 1.0),
+        })
+    }
+}
+
+struct LimiterState {
+    limit: f64,
+    in_flight: usize,
+    /// Moving average of recent latencies, in seconds.
+    latency: Option<f64>,
+    /// Lowest latency of the previous and of the current baseline period.
+    previous_min: Option<Duration>,
+    current_min: Option<Duration>,
+    period_start: Instant,
+    last_decrease: Instant,
+}
+
+impl LimiterState {
+    fn baseline(&self) -> Option<Duration> {
+        match (self.previous_min, self.current_min) {
+            (Some(previous), Some(current)) => Some(previous.min(current)),
+            (previous, current) => previous.or(current),
+        }
+    }
+}
+
+/// Caps how many payments are in flight with additive increase, multiplicative
+/// decrease: the limit grows by about one per round trip while payments are
+/// backing up and requests stay close to the no-load latency, and shrinks by
+/// `decrease_factor` at most once per round trip when they get slow or fail.
+pub struct ConcurrencyLimiter {
+    config: ConcurrencyConfig,
+    state: Mutex<LimiterState>,
+    released: Notify,
+}
+
+/// A slot taken from the limiter, given back when dropped.
+pub struct Permit {
+    limiter: Arc<ConcurrencyLimiter>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.limiter.state.lock() {
+            state.in_flight = state.in_flight.saturating_sub(1);
+        }
+        self.limiter.released.notify_one();
+    }
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(config: ConcurrencyConfig) -> Self {
+        ConcurrencyLimiter {
+            config,
+            state: Mutex::new(LimiterState {
+                limit: config.initial as f64,
+                in_flight: 0,
+                latency: None,
+                previous_min: None,
+                current_min: None,
+                period_start: Instant::now(),
+                last_decrease: Instant::now(),
+            }),
+            released: Notify::new(),
+        }
+    }
+
+    /// Waits until fewer payments than the limit are in flight.
+    pub async fn acquire(self: &Arc<Self>) -> Permit {
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            // Registers for a wake-up before checking, so a release in between is not missed.
+            released.as_mut().enable();
+
+            if let Ok(mut state) = self.state.lock()
+                && state.in_flight < state.limit as usize
+            {
+                state.in_flight += 1;
+                return Permit { limiter: self.clone() };
+            }
+
+            released.await;
+        }
+    }
+
+    /// Adjusts the limit after a payment request that took `latency`.
+    /// `overloaded` is set when the request failed in a way that suggests the
+    /// processor is struggling. `backlog` is the number of payments waiting.
+    pub fn record(&self, latency: Duration, overloaded: bool, backlog: usize) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+
+        let sample = latency.as_secs_f64();
+        let average = state.latency.map_or(sample, |average| average + ALPHA * (sample - average));
+        state.latency = Some(average);
+
+        if !overloaded {
+            state.current_min = Some(state.current_min.map_or(latency, |min| min.min(latency)));
+            if state.period_start.elapsed() >= BASELINE_PERIOD {
+                state.previous_min = state.current_min.take();
+                state.period_start = Instant::now();
+            }
+        }
+
+        let slow = state.baseline().is_some_and(|baseline| {
+            average > baseline.as_secs_f64() * self.config.latency_tolerance
+                && average > (baseline + LATENCY_SLACK).as_secs_f64()
+        });
+
+        let previous = state.limit;
+        if overloaded || slow {
+            // Requests sent before the last decrease reflect the old limit.
+            if state.last_decrease.elapsed() < latency {
+                return;
+            }
+            state.limit = (state.limit * self.config.decrease_factor).max(self.config.min as f64);
+            state.last_decrease = Instant::now();
+        } else if backlog > 0 && state.in_flight >= state.limit as usize {
+            // Only grow while the current limit is actually the bottleneck.
+            state.limit = (state.limit + 1.0 / state.limit).min(self.config.max as f64);
+        }
+
+        if state.limit as usize != previous as usize {
+            debug!("Payment concurrency limit now {} ({} in flight, {} queued)", state.limit as usize, state.in_flight, backlog);
+        }
+        drop(state);
+
+        // A higher limit may let waiting workers through.
+        self.released.notify_one();
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.lock().map(|state| state.limit as usize).unwrap_or(self.config.min)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().map(|state| state.in_flight).unwrap_or_default()
+    }
+}
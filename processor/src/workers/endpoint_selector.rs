@@ -1,26 +1,387 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::circuit_breaker::{Admission, BreakerConfig, BreakerState};
 use crate::cmd::App;
+use crate::endpoint_stats::EndpointStats;
+use crate::money::Cents;
+use crate::processor::Payment;
+use crate::processors::PaymentProcessor;
+use crate::workers::retry_policy::PaymentError;
+use crate::HealthCheck;
+
+/// What every second a payment waits on a slow endpoint costs, in the same
+/// unit as payment amounts.
+const LATENCY_COST_PER_SEC: f64 = 0.01;
+
+/// Everything a selector knows about one payment endpoint.
+pub struct EndpointView<'a> {
+    pub processor: &'a PaymentProcessor,
+    /// `None` when there is no recent enough health check.
+    pub health: Option<HealthCheck>,
+    pub stats: EndpointStats,
+    pub breaker: BreakerState,
+}
+
+impl EndpointView<'_> {
+    /// Not reported failing or too slow to answer within the processor's
+    /// `max_timeout`, and with a circuit breaker letting requests through.
+    /// Endpoints whose health is unknown, e.g. while their checks back off,
+    /// are left to the breaker alone.
+    pub fn viable(&self) -> bool {
+        self.health.is_none_or(|health| {
+            !health.failing
+                && Duration::from_millis(health.min_response_time as u64) <= self.processor.max_timeout
+        }) && self.breaker != BreakerState::Open
+    }
+
+    /// The worse of the reported minimum and the observed average latency.
+    pub fn expected_latency_ms(&self) -> f64 {
+        let min_response_time = self.health.map_or(0, |health| health.min_response_time);
+        (min_response_time as f64).max(self.stats.latency_ms)
+    }
+}
+
+pub struct RoutingContext<'a> {
+    /// Ordered by priority, most preferred first.
+    pub endpoints: &'a [EndpointView<'a>],
+    pub payment: &'a Payment,
+    /// Payments waiting in the queue, this one excluded.
+    pub backlog: usize,
+}
+
+pub enum Route {
+    /// Send the payment to the endpoint at this index of `endpoints`.
+    Send(usize),
+    /// Keep the payment queued for now, at most for `retry_after` when the
+    /// selector knows when it will route it anyway.
+    Defer { reason: String, retry_after: Option<Duration> },
+}
+
+/// A routing policy.
+pub trait EndpointSelector: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn select(&self, ctx: &RoutingContext) -> Route;
+
+    /// Estimated fees saved by the policy so far, if it keeps track.
+    fn fees_saved(&self) -> Option<Cents> {
+        None
+    }
+
+    /// Drops anything kept about a payment that will not be routed again.
+    fn forget(&self, _correlation_id: &str) {}
+
+    /// Drops anything kept about individual payments, e.g. on purge.
+    fn clear(&self) {}
+}
+
+/// Reads `ROUTING_STRATEGY` (`default-first`, `fee-aware`, `cost-weighted`,
+/// `lowest-latency` or `probabilistic-split`) and the settings of the chosen
+/// strategy.
+pub fn selector_from_env() -> crate::Result<Box<dyn EndpointSelector>> {
+    let strategy = env::var("ROUTING_STRATEGY").unwrap_or("default-first".to_string());
+    match strategy.as_str() {
+        "fee-aware" => {
+            let max_wait = env::var("ROUTING_MAX_WAIT_MS")
+                .unwrap_or("1000".to_string())
+                .parse::<u64>()
+                .map_err(|e| format!("Invalid ROUTING_MAX_WAIT_MS: {}", e))?;
+            let max_backlog = env::var("ROUTING_MAX_BACKLOG")
+                .unwrap_or("5000".to_string())
+                .parse::<usize>()
+                .map_err(|e| format!("Invalid ROUTING_MAX_BACKLOG: {}", e))?;
+            Ok(Box::new(FeeAware::new(Duration::from_millis(max_wait), max_backlog)))
+        }
+        "default-first" => Ok(Box::new(DefaultFirst)),
+        "cost-weighted" => Ok(Box::new(CostWeighted)),
+        "lowest-latency" => Ok(Box::new(LowestLatency)),
+        "probabilistic-split" => {
+            let default_share = env::var("ROUTING_SPLIT_DEFAULT_SHARE")
+                .unwrap_or("0.9".to_string())
+                .parse::<f64>()
+                .map_err(|e| format!("Invalid ROUTING_SPLIT_DEFAULT_SHARE: {}", e))?;
+            Ok(Box::new(ProbabilisticSplit { default_share: default_share.clamp(0.0, 1.0) }))
+        }
+        _ => Err(format!("Invalid ROUTING_STRATEGY: {}", strategy).into()),
+    }
+}
 
-const T_CLIENT: u32 = 10_000; // 10s
+pub struct RoutingConfig {
+    pub selector: Box<dyn EndpointSelector>,
+    pub breaker: BreakerConfig,
+    /// How old a health check may get before the endpoint's health is unknown.
+    pub health_max_age: Duration,
+}
+
+impl RoutingConfig {
+    /// Reads the routing strategy, the circuit breaker settings and
+    /// `HEALTH_MAX_AGE_MS`.
+    pub fn from_env() -> crate::Result<RoutingConfig> {
+        let health_max_age = env::var("HEALTH_MAX_AGE_MS")
+            .unwrap_or("15000".to_string())
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid HEALTH_MAX_AGE_MS: {}", e))?;
+
+        Ok(RoutingConfig {
+            selector: selector_from_env()?,
+            breaker: BreakerConfig::from_env()?,
+            health_max_age: Duration::from_millis(health_max_age),
+        })
+    }
+}
 
-pub async fn select_endpoint(app: &App) -> Result<String, String> {
+/// Picks the processor for `payment`, returning its index in
+/// `app.processors.active()` and its circuit breaker's admission.
+pub async fn select_endpoint(app: &App, payment: &Payment) -> Result<(usize, Admission), PaymentError> {
     let Ok(health) = app.db.get_health_check().await else {
-        return Err("Failed to retrieve health check".to_string());
+        return Err(PaymentError::Unavailable("Failed to retrieve health check".to_string()));
     };
 
-    let d_viable = !health.default_health_check.failing && 
-        health.default_health_check.min_response_time <= T_CLIENT;
-
-    let f_viable = !health.fallback_health_check.failing && 
-        health.fallback_health_check.min_response_time <= T_CLIENT;
-
-    match (d_viable, f_viable) {
-        // Case 1: D viable → D is optimal (lower cost)
-        (true, _) => Ok(app.payment_endpoint.clone()),
-        
-        // Case 2: D not viable, F viable → F is the only option
-        (false, true) => Ok(app.payment_fallback_endpoint.clone()),
-        
-        // Case 3: No viable → Do not send (wait/reject)
-        (false, false) => Err("No viable endpoint available - optimal action is to wait".to_string())
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut endpoints: Vec<EndpointView> = app.processors.active().iter()
+        .enumerate()
+        .map(|(i, processor)| EndpointView {
+            processor,
+            health: health.get(i, app.health_max_age, now),
+            stats: app.endpoint_stats.get(i),
+            breaker: app.endpoint_stats.breaker_state(i),
+        })
+        .collect();
+
+    // A half-open breaker may have handed out all its probe slots since the
+    // views were built, in which case that endpoint is treated as open and the
+    // selection runs again.
+    for _ in 0..=endpoints.len() {
+        let ctx = RoutingContext {
+            endpoints: &endpoints,
+            payment,
+            backlog: app.queue.len(),
+        };
+
+        match app.selector.select(&ctx) {
+            Route::Send(i) => match app.endpoint_stats.try_admit(i) {
+                Some(admission) => return Ok((i, admission)),
+                None => endpoints[i].breaker = BreakerState::Open,
+            },
+            Route::Defer { reason, retry_after: Some(retry_after) } => {
+                return Err(PaymentError::Deferred { reason, retry_after });
+            }
+            Route::Defer { reason, retry_after: None } => return Err(PaymentError::Unavailable(reason)),
+        }
+    }
+
+    Err(PaymentError::Unavailable("Every selected endpoint was refused by its circuit breaker".to_string()))
+}
+
+fn no_viable_endpoint() -> Route {
+    Route::Defer {
+        reason: "No viable endpoint available - optimal action is to wait".to_string(),
+        retry_after: None,
+    }
+}
+
+impl RoutingContext<'_> {
+    /// The payment amount, in the same unit as `LATENCY_COST_PER_SEC`.
+    fn amount(&self) -> f64 {
+        self.payment.amount.0 as f64 / 100.0
+    }
+
+    /// What the endpoint's latency costs. The payments queued behind this one
+    /// wait on it too, so the charge grows with the backlog.
+    fn latency_cost(&self, endpoint: &EndpointView) -> f64 {
+        LATENCY_COST_PER_SEC * endpoint.expected_latency_ms() / 1000.0 * (1 + self.backlog) as f64
+    }
+}
+
+/// The fee the payment is expected to pay plus what waiting on the endpoint costs.
+fn cost(ctx: &RoutingContext, endpoint: &EndpointView) -> f64 {
+    ctx.amount() * endpoint.processor.fee + ctx.latency_cost(endpoint)
+}
+
+/// Sends each payment to the viable endpoint with the lowest fee plus latency
+/// cost, but holds payments back for up to `max_wait` while the cheapest
+/// endpoint is down rather than paying the higher fee straight away. Gives up
+/// waiting early once the backlog reaches `max_backlog`.
+pub struct FeeAware {
+    max_wait: Duration,
+    max_backlog: usize,
+    state: Mutex<FeeAwareState>,
+}
+
+#[derive(Default)]
+struct FeeAwareState {
+    /// Since when the cheapest endpoint has not been viable.
+    unavailable_since: Option<Instant>,
+    /// Fee each held back payment would have paid had it not been held back.
+    held_back: HashMap<String, f64>,
+    fees_saved: f64,
+}
+
+impl FeeAware {
+    pub fn new(max_wait: Duration, max_backlog: usize) -> Self {
+        FeeAware {
+            max_wait,
+            max_backlog,
+            state: Mutex::new(FeeAwareState::default()),
+        }
+    }
+}
+
+impl EndpointSelector for FeeAware {
+    fn name(&self) -> &'static str {
+        "fee-aware"
+    }
+
+    fn select(&self, ctx: &RoutingContext) -> Route {
+        let Ok(mut state) = self.state.lock() else {
+            return DefaultFirst.select(ctx);
+        };
+
+        let best = ctx.endpoints.iter()
+            .enumerate()
+            .filter(|(_, e)| e.viable())
+            .min_by(|(_, a), (_, b)| cost(ctx, a).total_cmp(&cost(ctx, b)));
+        let cheapest = ctx.endpoints.iter()
+            .min_by(|a, b| a.processor.fee.total_cmp(&b.processor.fee));
+
+        if let Some(cheapest) = cheapest {
+            if cheapest.viable() {
+                state.unavailable_since = None;
+            } else {
+                let since = *state.unavailable_since.get_or_insert_with(Instant::now);
+                let remaining = self.max_wait.saturating_sub(since.elapsed());
+                if let Some((_, best)) = best
+                    && !remaining.is_zero()
+                    && ctx.backlog < self.max_backlog
+                {
+                    state.held_back.insert(ctx.payment.correlation_id.clone(), best.processor.fee);
+                    return Route::Defer {
+                        reason: format!("Waiting up to {:?} for the cheapest endpoint to recover", self.max_wait),
+                        retry_after: Some(remaining),
+                    };
+                }
+            }
+        }
+
+        let Some((i, best)) = best else {
+            return no_viable_endpoint();
+        };
+
+        if let Some(alternative_fee) = state.held_back.remove(&ctx.payment.correlation_id) {
+            state.fees_saved += ctx.amount() * (alternative_fee - best.processor.fee);
+        }
+        Route::Send(i)
+    }
+
+    fn fees_saved(&self) -> Option<Cents> {
+        let state = self.state.lock().ok()?;
+        Some(Cents((state.fees_saved * 100.0).round() as i64))
+    }
+
+    fn forget(&self, correlation_id: &str) {
+        if let Ok(mut state) = self.state.lock() {
+            state.held_back.remove(correlation_id);
+        }
+    }
+
+    fn clear(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.held_back.clear();
+        }
+    }
+}
+
+/// The most preferred viable endpoint.
+pub struct DefaultFirst;
+
+impl EndpointSelector for DefaultFirst {
+    fn name(&self) -> &'static str {
+        "default-first"
+    }
+
+    fn select(&self, ctx: &RoutingContext) -> Route {
+        ctx.endpoints.iter()
+            .position(|e| e.viable())
+            .map(Route::Send)
+            .unwrap_or_else(no_viable_endpoint)
+    }
+}
+
+/// The viable endpoint with the lowest expected cost: the payment's fee,
+/// inflated by the retries its error rate implies, plus a charge for its latency.
+pub struct CostWeighted;
+
+impl CostWeighted {
+    fn cost(ctx: &RoutingContext, endpoint: &EndpointView) -> f64 {
+        let success_rate = (1.0 - endpoint.stats.error_rate).max(0.01);
+        ctx.amount() * endpoint.processor.fee / success_rate + ctx.latency_cost(endpoint)
+    }
+}
+
+impl EndpointSelector for CostWeighted {
+    fn name(&self) -> &'static str {
+        "cost-weighted"
+    }
+
+    fn select(&self, ctx: &RoutingContext) -> Route {
+        ctx.endpoints.iter()
+            .enumerate()
+            .filter(|(_, e)| e.viable())
+            .min_by(|(_, a), (_, b)| Self::cost(ctx, a).total_cmp(&Self::cost(ctx, b)))
+            .map(|(i, _)| Route::Send(i))
+            .unwrap_or_else(no_viable_endpoint)
+    }
+}
+
+/// The viable endpoint expected to answer fastest, regardless of fees.
+pub struct LowestLatency;
+
+impl EndpointSelector for LowestLatency {
+    fn name(&self) -> &'static str {
+        "lowest-latency"
+    }
+
+    fn select(&self, ctx: &RoutingContext) -> Route {
+        ctx.endpoints.iter()
+            .enumerate()
+            .filter(|(_, e)| e.viable())
+            .min_by(|(_, a), (_, b)| a.expected_latency_ms().total_cmp(&b.expected_latency_ms()))
+            .map(|(i, _)| Route::Send(i))
+            .unwrap_or_else(no_viable_endpoint)
+    }
+}
+
+/// Sends `default_share` of the payments to the most preferred viable
+/// endpoint and splits the rest evenly between the other viable ones.
+pub struct ProbabilisticSplit {
+    pub default_share: f64,
+}
+
+impl EndpointSelector for ProbabilisticSplit {
+    fn name(&self) -> &'static str {
+        "probabilistic-split"
+    }
+
+    fn select(&self, ctx: &RoutingContext) -> Route {
+        let viable: Vec<usize> = ctx.endpoints.iter()
+            .enumerate()
+            .filter(|(_, e)| e.viable())
+            .map(|(i, _)| i)
+            .collect();
+
+        match viable.as_slice() {
+            [] => no_viable_endpoint(),
+            [only] => Route::Send(*only),
+            [first, rest @ ..] => {
+                if fastrand::f64() < self.default_share {
+                    Route::Send(*first)
+                } else {
+                    Route::Send(rest[fastrand::usize(..rest.len())])
+                }
+            }
+        }
     }
 }
@@ -1,23 +1,149 @@
+use crate::client::ProcessorClient;
 use crate::cmd::App;
-use crate::payment_client;
-use log::error;
-use std::time::Duration;
+use crate::payment_client::{self, HealthCheckError};
+use crate::protocol::FrameConfig;
+use crate::workers::call_budget::CallBudget;
+use crate::{PublishedHealthCheck, RecordedHealthCheck};
+use log::{error, info, warn};
+use std::env;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
 use tokio::time::sleep;
 
-pub async fn health_check_worker(app: App) {
+/// How often a follower asks its leader for the latest health checks.
+const FOLLOWER_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct HealthCheckConfig {
+    /// Minimum time between two calls to an endpoint's service-health.
+    pub interval: Duration,
+    /// Longest wait after a 429 without `Retry-After`.
+    pub max_backoff: Duration,
+    /// How long a single check may take.
+    pub timeout: Duration,
+    /// Socket of the processor to copy health checks from instead of polling.
+    pub leader_uds_path: Option<String>,
+}
+
+impl HealthCheckConfig {
+    /// Reads `HEALTH_CHECK_INTERVAL_MS`, `HEALTH_CHECK_MAX_BACKOFF_MS`,
+    /// `HEALTH_CHECK_TIMEOUT_MS` and `HEALTH_CHECK_LEADER_UDS_PATH`.
+    pub fn from_env() -> crate::Result<HealthCheckConfig> {
+        let interval = env::var("HEALTH_CHECK_INTERVAL_MS")
+            .unwrap_or("5000".to_string())
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid HEALTH_CHECK_INTERVAL_MS: {}", e))?;
+        let max_backoff = env::var("HEALTH_CHECK_MAX_BACKOFF_MS")
+            .unwrap_or("60000".to_string())
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid HEALTH_CHECK_MAX_BACKOFF_MS: {}", e))?;
+        let timeout = env::var("HEALTH_CHECK_TIMEOUT_MS")
+            .unwrap_or("2000".to_string())
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid HEALTH_CHECK_TIMEOUT_MS: {}", e))?;
+
+        Ok(HealthCheckConfig {
+            interval: Duration::from_millis(interval),
+            max_backoff: Duration::from_millis(max_backoff),
+            timeout: Duration::from_millis(timeout),
+            leader_uds_path: env::var("HEALTH_CHECK_LEADER_UDS_PATH").ok().filter(|path| !path.is_empty()),
+        })
+    }
+}
+
+pub async fn health_check_worker(app: App, config: HealthCheckConfig, frame: FrameConfig) {
+    match config.leader_uds_path {
+        Some(leader) => follow_leader(app, &leader, frame).await,
+        None => poll_endpoints(app, config).await,
+    }
+}
+
+/// Checks every endpoint concurrently, each on its own schedule.
+async fn poll_endpoints(app: App, config: HealthCheckConfig) {
+    let mut tasks = JoinSet::new();
+    for index in 0..app.processors.active().len() {
+        tasks.spawn(poll_endpoint(app.clone(), index, config.clone()));
+    }
+    while tasks.join_next().await.is_some() {}
+}
+
+async fn poll_endpoint(app: App, index: usize, config: HealthCheckConfig) {
+    let processor = &app.processors.active()[index];
+    let mut budget = CallBudget::new(config.interval, config.max_backoff);
+
     loop {
-        let health = match payment_client::health_check(&app).await {
-            Ok(health) => health,
+        budget.acquire().await;
+        let checked_at = Instant::now();
+        let check = match payment_client::health_check(&app, &processor.url, config.timeout).await {
+            Ok(health) => RecordedHealthCheck {
+                health,
+                checked_at: chrono::Utc::now().timestamp_millis(),
+            },
+            Err(HealthCheckError::RateLimited(retry_after)) => {
+                let delay = budget.on_rate_limited(retry_after);
+                warn!("Health check of {} rate limited, backing off for {:?}", processor.name, delay);
+                continue;
+            }
             Err(e) => {
-                error!("Health check failed: {}", e);
-                sleep(Duration::from_secs(1)).await;
+                error!("Health check of {} failed: {}", processor.name, e);
                 continue;
             }
         };
 
-        app.db.set_health_check(health).await.unwrap_or_else(|e| {
-            error!("Failed to set health check in database: {}", e);
-        });
-        sleep(Duration::from_secs(5)).await;
+        budget.on_success();
+        record_health_check(&app, index, check, checked_at).await;
     }
 }
+
+/// Mirrors the health checks of the leader processor, matching processors by
+/// name. Processors the leader has no recent check for end up unknown.
+async fn follow_leader(app: App, leader: &str, frame: FrameConfig) {
+    info!("Following health checks from {}", leader);
+    let mut client: Option<ProcessorClient> = None;
+
+    loop {
+        sleep(FOLLOWER_POLL_INTERVAL).await;
+
+        if client.as_ref().is_none_or(|client| client.is_closed()) {
+            client = match ProcessorClient::connect(leader, frame).await {
+                Ok(client) => Some(client),
+                Err(e) => {
+                    error!("Failed to connect to health check leader {}: {}", leader, e);
+                    continue;
+                }
+            };
+        }
+        let Some(connection) = client.as_ref() else {
+            continue;
+        };
+
+        let published: Vec<PublishedHealthCheck> = match connection.get_health_check().await {
+            Ok(published) => published,
+            Err(e) => {
+                error!("Failed to read health checks from leader {}: {}", leader, e);
+                client = None;
+                continue;
+            }
+        };
+
+        let now = chrono::Utc::now().timestamp_millis();
+        for (index, processor) in app.processors.active().iter().enumerate() {
+            let Some(published) = published.iter().find(|p| p.name == processor.name) else {
+                continue;
+            };
+            let age = Duration::from_millis(now.saturating_sub(published.check.checked_at).max(0) as u64);
+            let checked_at = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+            record_health_check(&app, index, published.check, checked_at).await;
+        }
+    }
+}
+
+/// `checked_at` is when the check started, which decides whether it may
+/// half-open a circuit breaker.
+async fn record_health_check(app: &App, index: usize, check: RecordedHealthCheck, checked_at: Instant) {
+    app.endpoint_stats.on_health_check(index, !check.health.failing, checked_at);
+
+    app.db.set_health_check(index, check).await.unwrap_or_else(|e| {
+        error!("Failed to set health check in database: {}", e);
+    });
+}
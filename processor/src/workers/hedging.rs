@@ -0,0 +1,180 @@
+use std::env;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use log::{debug, error, warn};
+use tokio::time::sleep;
+
+use crate::circuit_breaker::Admission;
+use crate::cmd::App;
+use crate::processor::Payment;
+use crate::payment_client;
+
+#[derive(Debug, Clone, Copy)]
+pub struct HedgeConfig {
+    /// Off by default: correlation IDs only dedupe within one processor, so a
+    /// hedged payment can end up charged by two of them.
+    pub enabled: bool,
+    /// Percentile of the endpoint's recent latency after which a second
+    /// request is sent.
+    pub percentile: f64,
+    /// Never hedge sooner than this.
+    pub min_delay: Duration,
+}
+
+impl HedgeConfig {
+    /// Reads `HEDGE_ENABLED`, `HEDGE_PERCENTILE` and `HEDGE_MIN_DELAY_MS`.
+    pub fn from_env() -> crate::Result<HedgeConfig> {
+        let enabled = env::var("HEDGE_ENABLED")
+            .unwrap_or("false".to_string())
+            .parse::<bool>()
+            .map_err(|e| format!("Invalid HEDGE_ENABLED: {}", e))?;
+        let percentile = env::var("HEDGE_PERCENTILE")
+            .unwrap_or("0.95".to_string())
+            .parse::<f64>()
+            .map_err(|e| format!("Invalid HEDGE_PERCENTILE: {}", e))?;
+        let min_delay = env::var("HEDGE_MIN_DELAY_MS")
+            .unwrap_or("50".to_string())
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid HEDGE_MIN_DELAY_MS: {}", e))?;
+
+        Ok(HedgeConfig {
+            enabled,
+            percentile: percentile.clamp(0.0, 1.0),
+            min_delay: Duration::from_millis(min_delay),
+        })
+    }
+}
+
+/// Sends the payment to the endpoint at `index` of `app.processors.active()`,
+/// which its circuit breaker let through with `admission`. With hedging
+/// enabled, once the endpoint has taken longer than its usual latency, the
+/// payment is also sent to the next healthy endpoint, and the first success
+/// wins. Returns the index of the endpoint whose answer counts.
+///
+/// Nothing stops the losing request from landing as well, which charges the
+/// customer twice. Only the winner is ever recorded; a loser that landed is
+/// reported by `check_loser`.
+pub async fn send_payment(
+    app: &App,
+    config: &HedgeConfig,
+    index: usize,
+    admission: Admission,
+    payment: &Payment,
+    created_at: DateTime<Utc>,
+) -> (usize, Result<(), reqwest::Error>) {
+    let deadline = app.endpoint_stats.latency_percentile(index, config.percentile)
+        .filter(|_| config.enabled)
+        .map(|latency| latency.max(config.min_delay));
+    let Some(deadline) = deadline else {
+        return (index, attempt(app.clone(), index, admission, payment.clone(), created_at).await);
+    };
+
+    let mut primary = Box::pin(attempt(app.clone(), index, admission, payment.clone(), created_at));
+    tokio::select! {
+        result = &mut primary => return (index, result),
+        _ = sleep(deadline) => {}
+    }
+
+    let Some((second, hedge_admission)) = hedge_endpoint(app, index).await else {
+        return (index, primary.await);
+    };
+    debug!(
+        "Payment {} not answered within {:?}, hedging to {}",
+        payment.correlation_id, deadline, app.processors.active()[second].name
+    );
+
+    let mut hedge = Box::pin(attempt(app.clone(), second, hedge_admission, payment.clone(), created_at));
+    let (first, result, other, pending) = tokio::select! {
+        result = &mut primary => (index, result, second, hedge),
+        result = &mut hedge => (second, result, index, primary),
+    };
+
+    if result.is_ok() {
+        let app = app.clone();
+        let payment = payment.clone();
+        tokio::spawn(async move {
+            let other_result = pending.await;
+            check_loser(&app, &payment, other, other_result).await;
+        });
+        return (first, result);
+    }
+
+    let other_result = pending.await;
+    if other_result.is_ok() {
+        check_loser(app, payment, first, result).await;
+        return (other, other_result);
+    }
+
+    // Both failed. A timeout is reported over any other error, so the caller
+    // verifies where the payment landed before retrying; otherwise the
+    // primary's error decides how to retry.
+    let timed_out = |result: &Result<(), reqwest::Error>| result.as_ref().is_err_and(|e| e.is_timeout());
+    if timed_out(&result) || (!timed_out(&other_result) && first == index) {
+        (first, result)
+    } else {
+        (other, other_result)
+    }
+}
+
+/// Sends one request and feeds its outcome to the endpoint's stats and to the
+/// concurrency limiter.
+async fn attempt(
+    app: App,
+    index: usize,
+    admission: Admission,
+    payment: Payment,
+    created_at: DateTime<Utc>,
+) -> Result<(), reqwest::Error> {
+    let processor = &app.processors.active()[index];
+    let timeout = payment_client::payment_timeout(&app, index).await;
+    let started = Instant::now();
+    let result = payment_client::create_payment(&app, processor, timeout, &payment, &created_at).await;
+
+    // Any answer other than a server error or a timeout means the endpoint is up.
+    let responded = match &result {
+        Ok(_) => true,
+        Err(e) => e.status().is_some_and(|status| !status.is_server_error()),
+    };
+    let latency = started.elapsed();
+    let timed_out = result.as_ref().is_err_and(|e| e.is_timeout());
+    app.endpoint_stats.record(index, admission, latency, responded, timed_out);
+    app.concurrency.record(latency, !responded, app.queue.len());
+    result
+}
+
+/// The first endpoint other than `primary`, in priority order, that is known
+/// to be healthy and whose circuit breaker lets a request through.
+async fn hedge_endpoint(app: &App, primary: usize) -> Option<(usize, Admission)> {
+    let health = app.db.get_health_check().await.ok()?;
+    let now = Utc::now().timestamp_millis();
+
+    (0..app.processors.active().len())
+        .filter(|&i| i != primary)
+        .filter(|&i| health.get(i, app.health_max_age, now).is_some_and(|health| !health.failing))
+        .find_map(|i| app.endpoint_stats.try_admit(i).map(|admission| (i, admission)))
+}
+
+/// Looks at how the request to the endpoint at `index` ended after the other
+/// one won. If it landed anyway, the payment was charged twice; it stays
+/// recorded once, with the winner.
+async fn check_loser(app: &App, payment: &Payment, index: usize, result: Result<(), reqwest::Error>) {
+    let processor = &app.processors.active()[index];
+    let landed = match result {
+        Ok(()) => true,
+        Err(e) if e.is_timeout() => {
+            match payment_client::get_payment(app, &processor.url, &payment.correlation_id).await {
+                Ok(found) => found.is_some(),
+                Err(e) => {
+                    warn!("Failed to verify hedged payment {} with {}: {}", payment.correlation_id, processor.name, e);
+                    return;
+                }
+            }
+        }
+        Err(_) => false,
+    };
+
+    if landed {
+        error!("Hedged payment {} was also charged by {}", payment.correlation_id, processor.name);
+    }
+}
@@ -1,3 +1,9 @@
+pub mod call_budget;
+pub mod concurrency_limiter;
 pub mod endpoint_selector;
 pub mod health_check_worker;
+pub mod hedging;
 pub mod payment_worker;
+pub mod retry_policy;
+pub mod snapshot_worker;
+pub mod wal_sync_worker;
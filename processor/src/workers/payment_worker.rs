@@ -1,80 +1,216 @@
 use crate::cmd::App;
+use crate::dead_letter::DeadLetter;
 use crate::processor::Payment;
+use crate::queue::QueuedPayment;
+use crate::processors::ProcessorId;
+use crate::tracker::{PaymentState, PaymentStatus};
 use crate::workers::endpoint_selector::select_endpoint;
-use crate::{db, payment_client, PaymentType};
-use async_channel::Receiver;
+use crate::workers::hedging::{self, HedgeConfig};
+use crate::workers::retry_policy::{PaymentError, RetryDecision, RetryPolicy};
+use crate::{db, payment_client};
 use log::{debug, error, warn};
-use std::time::Duration;
 use chrono::SubsecRound;
-use tokio::time::sleep;
 
-const WORKER_COUNT: usize = 3;
+/// Takes payments off the queue and processes each in its own task, with as
+/// many in flight at once as the concurrency limiter allows.
+pub async fn payment_worker(app: App, retry_policy: RetryPolicy, hedge_config: HedgeConfig) {
+    debug!("Payment worker started");
 
-pub async fn payment_worker(app: App) {    
-    for i in 0..WORKER_COUNT {
-        let worker_app = app.clone();
-        let worker_rx = app.payment_receiver.clone();
+    loop {
+        let Ok(entry) = app.queue.recv().await else {
+            error!("Payment worker shutting down");
+            break;
+        };
+
+        let permit = app.concurrency.acquire().await;
+        let task_app = app.clone();
         tokio::spawn(async move {
-            payment_processor_worker(worker_app, worker_rx, i).await;
+            handle_payment(&task_app, retry_policy, &hedge_config, entry).await;
+            drop(permit);
         });
     }
-    ()
 }
 
-async fn payment_processor_worker(app: App, rx: Receiver<Payment>, worker_id: usize) {
-    debug!("Payment processor worker {} started", worker_id);
+async fn handle_payment(app: &App, retry_policy: RetryPolicy, hedge_config: &HedgeConfig, mut entry: QueuedPayment) {
+    let correlation_id = entry.payment.correlation_id.clone();
+    set_state(app, &correlation_id, PaymentState::InFlight).await;
 
-    loop {
-        let Ok(payment) = rx.recv().await else {
-            error!("Payment processor worker {} shutting down", worker_id);
-            break;
-        };
+    let result = process_payment(app, hedge_config, &mut entry).await;
+    if !app.queue.is_pending(entry.id) {
+        debug!("Dropping payment {}, which was purged while in flight", correlation_id);
+        return;
+    }
+
+    let error = match result {
+        Ok(status) => {
+            set_state(app, &correlation_id, status).await;
+            ack(app, &entry).await;
+            return;
+        }
+        Err(error) => error,
+    };
 
-        loop {
-            match process_payment(&app, &payment).await {
-                Ok(_) => {
-                    break;
-                }
-                Err(_) => {
-                    sleep(Duration::from_millis(100)).await;
-                    continue;
-                }
+    if !matches!(error, PaymentError::Deferred { .. }) {
+        entry.attempts += 1;
+    }
+    match retry_policy.decide(&error, entry.attempts) {
+        RetryDecision::RetryAfter(delay) => {
+            debug!("Retrying payment {} in {:?} after {}", correlation_id, delay, error);
+            set_state(app, &correlation_id, PaymentState::Queued).await;
+            app.queue.retry(entry, delay);
+        }
+        RetryDecision::GiveUp => {
+            error!("Giving up on payment {} after {} attempts: {}", correlation_id, entry.attempts, error);
+            let letter = DeadLetter {
+                payment: entry.payment.clone(),
+                last_error: error.to_string(),
+                attempts: entry.attempts,
+                failed_at: chrono::Utc::now().timestamp_millis(),
+            };
+            if let Err(e) = app.dead_letters.add(letter).await {
+                // Leave it pending so it is retried after a restart rather than lost.
+                error!("Failed to dead-letter payment {}: {}", correlation_id, e);
+                return;
             }
+            set_state(app, &correlation_id, PaymentState::FailedPermanently).await;
+            ack(app, &entry).await;
         }
     }
 }
 
-async fn process_payment(app: &App, payment: &Payment) -> Result<(), String> {
+async fn set_state(app: &App, correlation_id: &str, status: impl Into<PaymentStatus>) {
+    app.tracker.set_state(correlation_id, status).await.unwrap_or_else(|e| {
+        error!("Failed to track payment {}: {}", correlation_id, e);
+    });
+}
+
+async fn ack(app: &App, entry: &QueuedPayment) {
+    app.selector.forget(&entry.payment.correlation_id);
+    app.queue.ack(entry.id).await.unwrap_or_else(|e| {
+        error!("Failed to ack payment {}: {}", entry.payment.correlation_id, e);
+    });
+}
+
+async fn process_payment(
+    app: &App,
+    hedge_config: &HedgeConfig,
+    entry: &mut QueuedPayment,
+) -> Result<PaymentStatus, PaymentError> {
+    // The payment processor already took it; sending it again would only
+    // earn a 422.
+    if let Some((processor, requested_at)) = entry.accepted {
+        return record_payment(app, entry, processor, requested_at).await;
+    }
+
+    let payment = &entry.payment;
     debug!("Processing payment: {:?}", payment);
 
-    let endpoint = select_endpoint(&app).await?;
+    // Re-sending a payment that already landed would only earn a 422 and leave
+    // it out of our totals, so check first.
+    if entry.timed_out
+        && let Some((processor, requested_at)) = verify_payment(app, payment).await?
+    {
+        return record_payment(app, entry, processor, requested_at).await;
+    }
+
+    let (index, admission) = select_endpoint(app, payment).await?;
+
     let created_at = chrono::Utc::now().round_subsecs(0);
-    let result = payment_client::create_payment(&app, &endpoint, payment, &created_at).await;
+    let (index, result) = hedging::send_payment(app, hedge_config, index, admission, payment, created_at).await;
+    let processor = &app.processors.active()[index];
+
     if let Err(e) = result {
+        if e.is_timeout() {
+            warn!("Payment {} timed out, verifying with the payment processors", payment.correlation_id);
+            entry.timed_out = true;
+            return match verify_payment(app, payment).await? {
+                Some((processor, requested_at)) => record_payment(app, entry, processor, requested_at).await,
+                None => Err(PaymentError::from_reqwest(e)),
+            };
+        }
+
+        // Duplicates are turned away before they get here, so this is most
+        // likely an earlier attempt of ours that landed, e.g. before a restart.
         if e.status() == Some(reqwest::StatusCode::UNPROCESSABLE_ENTITY) {
+            if let Some((processor, requested_at)) = verify_payment(app, payment).await? {
+                return record_payment(app, entry, processor, requested_at).await;
+            }
             warn!("Payment already exists: {}", e);
-            return Ok(());
+            return Ok(PaymentState::Duplicate.into());
         }
 
         if e.status() != Some(reqwest::StatusCode::INTERNAL_SERVER_ERROR) {
             error!("Failed to create payment: {}", e);
         }
 
-        return Err(e.to_string());
+        return Err(PaymentError::from_reqwest(e));
     }
 
-    let payment_type = if endpoint == app.payment_endpoint {
-        PaymentType::Default
-    } else {
-        PaymentType::Fallback
-    };
+    record_payment(app, entry, processor.id, created_at.timestamp_millis()).await
+}
+
+/// Asks every payment processor whether it has the payment, returning where it
+/// landed and the `requestedAt` it was recorded with. Processors that cannot be
+/// asked are skipped; it only fails if none of them answered. A payment found
+/// on more than one processor was charged more than once, but is still only
+/// recorded with the first.
+async fn verify_payment(app: &App, payment: &Payment) -> Result<Option<(ProcessorId, i64)>, PaymentError> {
+    let mut answered = false;
+    let mut last_error = None;
+    let mut landed: Option<(ProcessorId, i64)> = None;
+
+    for processor in app.processors.active() {
+        let found = match payment_client::get_payment(app, &processor.url, &payment.correlation_id).await {
+            Ok(found) => found,
+            Err(e) => {
+                warn!("Failed to verify payment {} with {}: {}", payment.correlation_id, processor.name, e);
+                last_error = Some(e);
+                continue;
+            }
+        };
+        answered = true;
+
+        let Some(dto) = found else {
+            continue;
+        };
+        debug!("Payment {} found on {}", payment.correlation_id, processor.name);
+        if landed.is_some() {
+            error!("Payment {} was also charged by {}", payment.correlation_id, processor.name);
+            continue;
+        }
+
+        let requested_at = chrono::DateTime::parse_from_rfc3339(&dto.requested_at)
+            .map_err(|e| PaymentError::Transient(format!("Invalid requestedAt {}: {}", dto.requested_at, e)))?
+            .timestamp_millis();
+        landed = Some((processor.id, requested_at));
+    }
+
+    match last_error {
+        Some(e) if !answered => Err(PaymentError::Transient(format!("Failed to verify payment: {}", e))),
+        _ => Ok(landed),
+    }
+}
+
+async fn record_payment(
+    app: &App,
+    entry: &mut QueuedPayment,
+    processor: ProcessorId,
+    requested_at: i64,
+) -> Result<PaymentStatus, PaymentError> {
+    // A purge wipes the processors as well, so there is nothing to record.
+    if !app.queue.is_pending(entry.id) {
+        return Err(PaymentError::Permanent("Payment was purged".to_string()));
+    }
 
     let payment_db = db::Payment {
-        amount: payment.amount,
-        requested_at: created_at.timestamp_millis(),
-        payment_type
+        amount: entry.payment.amount,
+        requested_at,
+        processor
     };
 
-    app.db.insert(payment_db).await.map_err(|e| e.to_string())?;
-    Ok(())
+    if let Err(e) = app.db.insert(payment_db).await {
+        entry.accepted = Some((processor, requested_at));
+        return Err(PaymentError::Transient(format!("Failed to record payment: {}", e)));
+    }
+    Ok(PaymentStatus::succeeded(processor))
 }
@@ -0,0 +1,91 @@
+use std::env;
+use std::time::Duration;
+
+/// Why processing a payment failed, which decides whether it is worth retrying.
+#[derive(Debug)]
+pub enum PaymentError {
+    /// Nothing was sent because no endpoint is viable right now.
+    Unavailable(String),
+    /// Nothing was sent because the routing policy holds the payment back,
+    /// for no longer than `retry_after`. Not a failed attempt.
+    Deferred { reason: String, retry_after: Duration },
+    /// Server errors, timeouts and connection failures.
+    Transient(String),
+    /// Client errors that will fail the same way on every attempt.
+    Permanent(String),
+}
+
+impl PaymentError {
+    pub fn from_reqwest(e: reqwest::Error) -> PaymentError {
+        match e.status() {
+            Some(status) if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                PaymentError::Permanent(e.to_string())
+            }
+            _ => PaymentError::Transient(e.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for PaymentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaymentError::Unavailable(e) => write!(f, "unavailable: {}", e),
+            PaymentError::Deferred { reason, .. } => write!(f, "deferred: {}", reason),
+            PaymentError::Transient(e) => write!(f, "transient: {}", e),
+            PaymentError::Permanent(e) => write!(f, "permanent: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RetryDecision {
+    RetryAfter(Duration),
+    GiveUp,
+}
+
+/// Exponential backoff with full jitter, capped in both delay and attempts.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub max_attempts: u32,
+}
+
+impl RetryPolicy {
+    /// Reads `RETRY_BASE_DELAY_MS`, `RETRY_MAX_DELAY_MS` and `RETRY_MAX_ATTEMPTS`.
+    pub fn from_env() -> crate::Result<RetryPolicy> {
+        let parse = |name: &str, default: &str| {
+            env::var(name)
+                .unwrap_or(default.to_string())
+                .parse::<u64>()
+                .map_err(|e| format!("Invalid {}: {}", name, e))
+        };
+
+        Ok(RetryPolicy {
+            base_delay: Duration::from_millis(parse("RETRY_BASE_DELAY_MS", "100")?),
+            max_delay: Duration::from_millis(parse("RETRY_MAX_DELAY_MS", "10000")?),
+            max_attempts: u32::try_from(parse("RETRY_MAX_ATTEMPTS", "10")?).unwrap_or(u32::MAX),
+        })
+    }
+
+    /// Decides what to do after the `attempts`-th failed attempt. Payments that
+    /// were never sent are retried indefinitely, since waiting for a processor
+    /// to come back is the only option. Deferred ones back off as usual but
+    /// never past the end of the wait the routing policy asked for.
+    pub fn decide(&self, error: &PaymentError, attempts: u32) -> RetryDecision {
+        match error {
+            PaymentError::Permanent(_) => RetryDecision::GiveUp,
+            PaymentError::Deferred { retry_after, .. } => RetryDecision::RetryAfter(self.backoff(attempts).min(*retry_after)),
+            PaymentError::Transient(_) if attempts >= self.max_attempts => RetryDecision::GiveUp,
+            _ => RetryDecision::RetryAfter(self.backoff(attempts)),
+        }
+    }
+
+    /// A random delay between zero and `base_delay * 2^attempts`, capped at `max_delay`.
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let ceiling = self.base_delay
+            .saturating_mul(1u32.checked_shl(attempts).unwrap_or(u32::MAX))
+            .min(self.max_delay);
+        ceiling.mul_f64(fastrand::f64())
+    }
+}
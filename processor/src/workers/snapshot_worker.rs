@@ -0,0 +1,14 @@
+use crate::cmd::App;
+use log::error;
+use std::time::Duration;
+use tokio::time::sleep;
+
+pub async fn snapshot_worker(app: App, interval: Duration) {
+    loop {
+        sleep(interval).await;
+
+        app.db.snapshot().await.unwrap_or_else(|e| {
+            error!("Failed to snapshot payments: {}", e);
+        });
+    }
+}